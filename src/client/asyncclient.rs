@@ -0,0 +1,204 @@
+use client::{
+    connection::Connection, delivery, publish_packet, subscribe_packet, unsubscribe_packet, Command, DeliveryToken, Notification,
+    PublishBuilder, Request, UserHandle,
+};
+use crossbeam_channel::{self, TryRecvError};
+use error::{ClientError, ConnectError};
+use futures::{future, sync::mpsc, task::AtomicTask, Async, Future, Poll, Sink, Stream};
+use mqtt311::QoS;
+use std::sync::Arc;
+use MqttOptions;
+
+/// Same as `MqttClient` but requests return futures which resolve once the request
+/// is queued to the event loop. Never blocks the calling task
+#[derive(Clone)]
+pub struct AsyncMqttClient {
+    request_tx: mpsc::Sender<Request>,
+    command_tx: mpsc::Sender<Command>,
+    max_packet_size: usize,
+}
+
+/// Notifications of the event loop. Ends when the event loop stops. Note that
+/// `NotificationOverflow::Block` blocks the event loop while the stream is full.
+/// Use other policies when the event loop and the stream share an executor
+pub struct NotificationStream {
+    rx: crossbeam_channel::Receiver<Notification>,
+    task: Arc<AtomicTask>,
+}
+
+impl AsyncMqttClient {
+    /// Runs the event loop in a new thread. See `MqttClient::start`
+    pub fn start(opts: MqttOptions) -> Result<(Self, NotificationStream), ConnectError> {
+        let max_packet_size = opts.max_packet_size();
+        let user_handle = Connection::run(opts)?;
+        Ok(AsyncMqttClient::new(user_handle, max_packet_size))
+    }
+
+    /// Returns the event loop as a future to be spawned on the caller's executor.
+    /// See `Connection::eventloop`
+    pub fn eventloop(opts: MqttOptions) -> (Self, NotificationStream, impl Future<Item = (), Error = ConnectError> + Send) {
+        let max_packet_size = opts.max_packet_size();
+        let (user_handle, eventloop) = Connection::eventloop(opts);
+        let (client, notifications) = AsyncMqttClient::new(user_handle, max_packet_size);
+        (client, notifications, eventloop)
+    }
+
+    fn new(user_handle: UserHandle, max_packet_size: usize) -> (Self, NotificationStream) {
+        let UserHandle {
+            request_tx,
+            command_tx,
+            notification_rx,
+            notification_task,
+        } = user_handle;
+
+        let client = AsyncMqttClient { request_tx,
+                                       command_tx,
+                                       max_packet_size };
+        let notifications = NotificationStream { rx: notification_rx,
+                                                 task: notification_task };
+        (client, notifications)
+    }
+
+    pub fn publish<S, V>(&self, topic: S, qos: QoS, payload: V) -> impl Future<Item = (), Error = ClientError>
+        where S: Into<String>,
+              V: Into<Vec<u8>>
+    {
+        self.publish_with(PublishBuilder::new(topic, qos, payload))
+    }
+
+    /// Publishes with all the options of the builder (retain, dup). Invalid
+    /// topic names fail the future instead of being sent to the broker
+    pub fn publish_with(&self, builder: PublishBuilder) -> impl Future<Item = (), Error = ClientError> {
+        let tx = self.request_tx.clone();
+        future::result(publish_packet(builder, self.max_packet_size)).and_then(move |publish| send(tx, Request::Publish(publish)))
+    }
+
+    /// Resolves with a token which resolves when the broker acknowledges the publish
+    pub fn publish_with_token(&self, builder: PublishBuilder) -> impl Future<Item = DeliveryToken, Error = ClientError> {
+        let tx = self.request_tx.clone();
+        let publish = publish_packet(builder, self.max_packet_size);
+        future::result(publish).and_then(move |publish| {
+                                   let (delivery_tx, token) = delivery::channel();
+                                   let request = Request::TrackedPublish(publish, delivery_tx);
+                                   send(tx, request).map(move |_| token)
+                               })
+    }
+
+    pub fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> impl Future<Item = (), Error = ClientError> {
+        self.subscribe_many(vec![(topic.into(), qos)])
+    }
+
+    /// Subscribes to all the topics with a single subscribe packet. Broker's
+    /// response for each topic is notified with `Notification::SubAck`
+    pub fn subscribe_many(&self, topics: Vec<(String, QoS)>) -> impl Future<Item = (), Error = ClientError> {
+        let tx = self.request_tx.clone();
+        future::result(subscribe_packet(topics)).and_then(move |subscribe| send(tx, Request::Subscribe(subscribe)))
+    }
+
+    pub fn unsubscribe<S: Into<String>>(&self, topic: S) -> impl Future<Item = (), Error = ClientError> {
+        send(self.request_tx.clone(), Request::Unsubscribe(unsubscribe_packet(topic.into())))
+    }
+
+    pub fn pause(&self) -> impl Future<Item = (), Error = ClientError> {
+        send(self.command_tx.clone(), Command::Pause)
+    }
+
+    pub fn resume(&self) -> impl Future<Item = (), Error = ClientError> {
+        send(self.command_tx.clone(), Command::Resume)
+    }
+
+    pub fn disconnect(&self) -> impl Future<Item = (), Error = ClientError> {
+        send(self.request_tx.clone(), Request::Disconnect)
+    }
+}
+
+fn send<T>(tx: mpsc::Sender<T>, item: T) -> impl Future<Item = (), Error = ClientError>
+    where ClientError: From<mpsc::SendError<T>>
+{
+    tx.send(item).map(|_| ()).map_err(ClientError::from)
+}
+
+impl Stream for NotificationStream {
+    type Item = Notification;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Notification>, ()> {
+        match self.rx.try_recv() {
+            Ok(notification) => return Ok(Async::Ready(Some(notification))),
+            Err(TryRecvError::Disconnected) => return Ok(Async::Ready(None)),
+            Err(TryRecvError::Empty) => (),
+        }
+
+        // register before checking again to not miss a notification sent in between
+        self.task.register();
+        match self.rx.try_recv() {
+            Ok(notification) => Ok(Async::Ready(Some(notification))),
+            Err(TryRecvError::Disconnected) => Ok(Async::Ready(None)),
+            Err(TryRecvError::Empty) => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncMqttClient;
+    use client::Notification;
+    use error::ClientError;
+    use futures::{Future, Stream};
+    use mqtt311::QoS;
+    use mqttoptions::{MqttOptions, NotificationOverflow, ReconnectOptions};
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+    use tokio::runtime::current_thread;
+
+    fn read_packet<R: Read>(stream: &mut R) -> Vec<u8> {
+        let mut packet = vec![0; 2];
+        stream.read_exact(&mut packet).unwrap();
+        let mut body = vec![0; packet[1] as usize];
+        stream.read_exact(&mut body).unwrap();
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn async_client_and_notifications_should_share_the_eventloop_executor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (packet_tx, packet_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            packet_tx.send(read_packet(&mut stream)).unwrap();
+        });
+
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never)
+                                                                .set_notification_overflow(NotificationOverflow::DropNewest);
+        let (client, notifications, eventloop) = AsyncMqttClient::eventloop(opts);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.spawn(eventloop.map_err(|e| panic!("Event loop failed. Error = {:?}", e)));
+        rt.spawn(client.publish("hello/world", QoS::AtMostOnce, vec![1, 2, 3])
+                       .map_err(|e| panic!("Publish failed. Error = {:?}", e)));
+
+        match client.subscribe_many(Vec::new()).wait() {
+            Err(ClientError::ZeroSubscriptions) => (),
+            v => panic!("Should throw zero subscriptions error. Got = {:?}", v),
+        }
+
+        // stream ends when the broker closes the connection as the event loop doesn't reconnect
+        let notifications = rt.block_on(notifications.collect()).unwrap();
+        match notifications.as_slice() {
+            [Notification::Connected(false, _), Notification::Disconnected(_)] => (),
+            n => panic!("Invalid notifications: {:?}", n),
+        }
+
+        let publish = packet_rx.recv().unwrap();
+        assert_eq!(publish[0], 0x30);
+        assert_eq!(&publish[publish.len() - 3..], &[1, 2, 3]);
+    }
+}
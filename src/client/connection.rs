@@ -1,8 +1,11 @@
 use client::{
+    cloud,
     mqttasync,
     mqttstate::MqttState,
-    network::stream::NetworkStream,
+    network::stream::{NetworkStream, NetworkStreamBuilder},
     prepend::{Prepend, StreamExt},
+    session::{self, Session},
+    websocket,
     Notification,
     Request,
 };
@@ -10,20 +13,29 @@ use codec::MqttCodec;
 use crossbeam_channel;
 use error::{ConnectError, NetworkError, PollError};
 use futures::{
-    future,
+    future::{self, Loop},
     stream::{self, SplitStream},
     sync::mpsc,
+    task::{self, AtomicTask},
+    Async,
     Future,
     Sink,
     Stream,
 };
 use mqtt311::Packet;
-use mqttoptions::{ConnectionMethod, MqttOptions, ReconnectOptions};
-use std::{cell::RefCell, rc::Rc, thread, time::Duration};
+use mqttoptions::{
+    self, ConnectionMethod, Endpoint, EndpointOrder, MqttOptions, NotificationOverflow, ReconnectOptions, SecurityOptions,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 use tokio::runtime::current_thread;
 use tokio_codec::Framed;
-use tokio_timer::Timeout;
-use crossbeam_channel::Sender;
+use tokio_timer::{Delay, Timeout};
+use crossbeam_channel::{Sender, TrySendError};
 use futures::sync::mpsc::Receiver;
 use client::UserHandle;
 use tokio_timer::timeout;
@@ -34,10 +46,16 @@ use client::Command;
 //  https://github.com/tokio-rs/tokio-core/issues/182
 
 pub struct Connection {
-    mqtt_state: Rc<RefCell<MqttState>>,
-    notification_tx: Sender<Notification>,
+    mqtt_state: Arc<Mutex<MqttState>>,
+    notification_tx: NotificationSender,
     connection_tx: Option<Sender<Result<(), ConnectError>>>,
     connection_count: u32,
+    reconnect_attempts: u32,
+    last_reconnect_delay: Duration,
+    connected_at: Option<Instant>,
+    endpoints: Vec<Endpoint>,
+    endpoint_index: usize,
+    failed_endpoints: usize,
     mqttoptions: MqttOptions,
 }
 
@@ -45,28 +63,25 @@ impl Connection {
     /// Takes mqtt options and tries to create initial connection on current thread and handles
     /// connection events in a new thread if the initial connection is successful
     pub fn run(mqttoptions: MqttOptions) -> Result<UserHandle, ConnectError> {
-        let (notification_tx, notification_rx) = crossbeam_channel::bounded(10);
-        let (request_tx, request_rx) = mpsc::channel::<Request>(10);
-        let (command_tx, command_rx) = mpsc::channel::<Command>(5);
-
         let (connection_tx, connection_rx) = crossbeam_channel::bounded(1);
         let reconnect_option = mqttoptions.reconnect_opts();
+        let (user_handle, channels) = EventLoopChannels::new(&mqttoptions);
+        // unacknowledged messages of the previous run of the process
+        let session = session::load_session(&mqttoptions)?;
 
         // start the network thread to handle all mqtt network io
         thread::spawn(move || {
-            let mqtt_state = Rc::new(RefCell::new(MqttState::new(mqttoptions.clone())));
-            let mut connection = Connection { mqtt_state,
-                                              notification_tx,
-                                              connection_tx: Some(connection_tx),
-                                              connection_count: 0,
-                                              mqttoptions };
-
-            connection.mqtt_eventloop(request_rx, command_rx)
-        });
-
+            let (notification_tx, request_rx, command_rx) = channels.split();
+            let connection = Connection::new(mqttoptions, session, notification_tx, Some(connection_tx.clone()));
+            let eventloop = connection.mqtt_eventloop(request_rx, command_rx);
 
-        // return user handle to client to send requests and handle notifications
-        let user_handle = UserHandle{request_tx, command_tx, notification_rx};
+            // event loop only fails when the initial connection fails. Return the error
+            // to the user waiting below
+            let mut rt = current_thread::Runtime::new().unwrap();
+            if let Err(e) = rt.block_on(eventloop) {
+                connection_tx.send(Err(e)).unwrap();
+            }
+        });
 
         match reconnect_option {
             ReconnectOptions::AfterFirstSuccess(_) => {
@@ -77,141 +92,384 @@ impl Connection {
                 connection_rx.recv()??;
                 Ok(user_handle)
             }
-            ReconnectOptions::Always(_) => Ok(user_handle)
+            ReconnectOptions::Always(_) | ReconnectOptions::Backoff(_) => Ok(user_handle)
         }
     }
 
-    // NOTE: We need to use same reactor across threads because io resources (framed) will
-    //       bind to reactor lazily.
-    //       You'll face `reactor gone` error if `framed` is used again with a new recator
-    fn mqtt_eventloop(&mut self, request_rx: Receiver<Request>, command_rx: Receiver<Command>) {
-        let reconnect_option = self.mqttoptions.reconnect_opts();
-        let previous_request_stream = self.request_stream(request_rx);
-        let mut command_stream = self.command_stream(command_rx);
-        let mut network_request_stream = self.network_request_stream(previous_request_stream);
+    /// Returns the handle to the user and the event loop as a future which is driven by
+    /// the caller's executor. The future is `Send` and can be spawned on the threadpool
+    /// runtime (`tokio::spawn`) as well as on `tokio::runtime::current_thread`. Many
+    /// connections can share the same executor.
+    ///
+    /// Future resolves when the event loop stops reconnecting and fails if the initial
+    /// connection fails with `ReconnectOptions::Never` or `ReconnectOptions::AfterFirstSuccess`
+    /// (or if the session store can't be loaded)
+    pub fn eventloop(mqttoptions: MqttOptions) -> (UserHandle, impl Future<Item = (), Error = ConnectError> + Send) {
+        let (user_handle, channels) = EventLoopChannels::new(&mqttoptions);
+        let (notification_tx, request_rx, command_rx) = channels.split();
+        let session = session::load_session(&mqttoptions).map_err(ConnectError::Io);
+        let eventloop = future::result(session).and_then(move |session| {
+                                                    let connection = Connection::new(mqttoptions, session, notification_tx, None);
+                                                    connection.mqtt_eventloop(request_rx, command_rx)
+                                                });
 
-        'reconnection: loop {
-            let mqtt_connect_future = self.mqtt_connect();
-            let mqtt_connect_deadline = Timeout::new(mqtt_connect_future, Duration::from_secs(30));
+        (user_handle, eventloop)
+    }
 
-            // mqtt connection
-            let mut rt = current_thread::Runtime::new().unwrap();
-            let framed = match rt.block_on(mqtt_connect_deadline) {
-                Ok(framed) => {
-                    debug!("Mqtt connection successful!!");
-                    self.handle_connection_success();
-                    framed
-                },
-                Err(e) => {
-                    error!("Connection error = {:?}", e);
-                    self.handle_connection_error(e);
-                    if should_reconnect_again(reconnect_option) {
-                        continue 'reconnection
-                    } else {
-                        break 'reconnection
-                    }
-                }
-            };
+    fn new(mqttoptions: MqttOptions,
+           session: Session,
+           notification_tx: NotificationSender,
+           connection_tx: Option<Sender<Result<(), ConnectError>>>)
+           -> Connection {
+        let mut mqtt_state = MqttState::new(mqttoptions.clone());
+        mqtt_state.restore_session(session);
 
-            let (network_sink, network_stream) = framed.split();
-            let network_reply_stream = self.network_reply_stream(network_stream);
+        let mqtt_state = Arc::new(Mutex::new(mqtt_state));
+        let mut endpoints = mqttoptions.endpoints();
+        if mqttoptions.endpoint_order() == EndpointOrder::Shuffled {
+            mqttoptions::shuffle_endpoints(&mut endpoints);
+        }
 
-            let mqtt_stream = mqttasync::new(network_reply_stream, network_sink, network_request_stream, command_stream);
-            let (mqtt_sink, mqtt_stream) = mqtt_stream.split();
+        Connection { mqtt_state,
+                     notification_tx,
+                     connection_tx,
+                     connection_count: 0,
+                     reconnect_attempts: 0,
+                     last_reconnect_delay: Duration::from_secs(0),
+                     connected_at: None,
+                     endpoints,
+                     endpoint_index: 0,
+                     failed_endpoints: 0,
+                     mqttoptions }
+    }
 
-            let mqtt_future = mqtt_stream.forward(mqtt_sink);
+    // NOTE: All the connections of the event loop should run on the same executor because
+    //       io resources (framed) bind to the reactor lazily.
+    //       You'll face `reactor gone` error if `framed` is used again with a new recator
+    fn mqtt_eventloop(mut self,
+                      request_rx: Receiver<Request>,
+                      command_rx: Receiver<Command>)
+                      -> impl Future<Item = (), Error = ConnectError> {
+        let previous_request_stream = self.request_stream(request_rx);
+        let command_stream = self.command_stream(command_rx);
+        let network_request_stream = self.network_request_stream(previous_request_stream);
+        let start = (self, network_request_stream, command_stream, Duration::from_secs(0));
+
+        future::loop_fn(start, |(connection, network_request_stream, command_stream, delay)| {
+            // reconnection delay is a timer to not block the executor
+            Delay::new(Instant::now() + delay).map_err(ConnectError::Timer)
+                                              .and_then(move |_| {
+                                                  let mqtt_connect_future = connection.mqtt_connect();
+                                                  let mqtt_connect_deadline =
+                                                      Timeout::new(mqtt_connect_future, Duration::from_secs(30));
+                                                  mqtt_connect_deadline.then(move |result| future::ok((connection, result)))
+                                              })
+                                              .and_then(move |(connection, result)| {
+                                                  connection.mqtt_io(result, network_request_stream, command_stream)
+                                              })
+        })
+    }
 
-            // mqtt event loop
-            match rt.block_on(mqtt_future) {
-                Err(PollError::Network((e, mut r, c))) => {
-                    error!("Event loop disconnect. Error = {:?}", e);
-                    self.merge_network_request_stream(&mut r);
-                    network_request_stream = r;
-                    command_stream = c;
+    /// Handles the result of a connection attempt. Runs the mqtt io till the connection
+    /// is lost and decides if and when to reconnect
+    fn mqtt_io<R, C>(mut self,
+                     connection_result: Result<(MqttFramed, bool), timeout::Error<ConnectError>>,
+                     network_request_stream: Prepend<R>,
+                     command_stream: C)
+                     -> impl Future<Item = Loop<(), (Connection, Prepend<R>, C, Duration)>, Error = ConnectError>
+        where R: PacketStream,
+              C: CommandStream
+    {
+        let framed = match connection_result {
+            Ok((framed, session_present)) => {
+                debug!("Mqtt connection successful!!");
+                self.handle_connection_success(session_present);
+                framed
+            }
+            Err(e) => {
+                error!("Connection error = {:?}", e);
+                let e = match e.into_inner() {
+                    Some(e) => e,
+                    None => ConnectError::Timeout,
+                };
+
+                // every refusal is reported, including the ones of endpoints failed over from
+                if let ConnectError::MqttConnectionRefused(code) = e {
+                    self.notification_tx.send(Notification::ConnectionRefused(code));
                 }
-                Err(PollError::StreamClosed(mut r, c)) => {
-                    error!("Stream closed error");
-                    self.merge_network_request_stream(&mut r);
-                    network_request_stream = r;
-                    command_stream = c;
+
+                if self.failover() {
+                    let next = Loop::Continue((self, network_request_stream, command_stream, Duration::from_secs(0)));
+                    return future::Either::A(future::ok(next));
                 }
-                _ => panic!("Shouldn't happen")
-            }
 
-            if should_reconnect_again(reconnect_option) {
-                continue 'reconnection
-            } else {
-                break 'reconnection
+                let next = match self.handle_connection_error(e) {
+                    Some(e) => Err(e),
+                    None => Ok(self.next_attempt(network_request_stream, command_stream)),
+                };
+
+                return future::Either::A(future::result(next));
             }
-        }
+        };
+
+        let (network_sink, network_stream) = framed.split();
+        let network_reply_stream = self.network_reply_stream(network_stream);
+
+        let mqtt_stream = mqttasync::new(network_reply_stream, network_sink, network_request_stream, command_stream);
+        let (mqtt_sink, mqtt_stream) = mqtt_stream.split();
+
+        let mqtt_future = mqtt_stream.forward(mqtt_sink);
+
+        // mqtt event loop
+        let next = mqtt_future.then(move |result| {
+                                  let next = match result {
+                                      Err(PollError::Network((NetworkError::ReturnToPrimary, mut r, c))) => {
+                                          debug!("Returning to primary endpoint");
+                                          self.notification_tx.send(Notification::Disconnected(NetworkError::ReturnToPrimary));
+                                          self.merge_network_request_stream(&mut r);
+                                          self.handle_disconnection();
+
+                                          self.endpoint_index = 0;
+                                          Loop::Continue((self, r, c, Duration::from_secs(0)))
+                                      }
+                                      Err(PollError::Network((NetworkError::TokenExpiring, mut r, c))) => {
+                                          debug!("Reconnecting with a fresh token");
+                                          self.notification_tx.send(Notification::Disconnected(NetworkError::TokenExpiring));
+                                          self.merge_network_request_stream(&mut r);
+                                          self.handle_disconnection();
+                                          Loop::Continue((self, r, c, Duration::from_secs(0)))
+                                      }
+                                      Err(PollError::Network((e, mut r, c))) => {
+                                          error!("Event loop disconnect. Error = {:?}", e);
+                                          self.notification_tx.send(Notification::Disconnected(e));
+                                          self.merge_network_request_stream(&mut r);
+                                          self.handle_disconnection();
+                                          self.next_attempt(r, c)
+                                      }
+                                      Err(PollError::StreamClosed(mut r, c)) => {
+                                          error!("Stream closed error");
+                                          let notification = Notification::Disconnected(NetworkError::NetworkStreamClosed);
+                                          self.notification_tx.send(notification);
+                                          self.merge_network_request_stream(&mut r);
+                                          self.handle_disconnection();
+                                          self.next_attempt(r, c)
+                                      }
+                                      _ => panic!("Shouldn't happen"),
+                                  };
+
+                                  Ok(next)
+                              });
+
+        future::Either::B(next)
     }
 
+    /// Continues the event loop after the reconnection delay or breaks it if the
+    /// event loop shouldn't reconnect anymore
+    fn next_attempt<R, C>(mut self, network_request_stream: R, command_stream: C) -> Loop<(), (Connection, R, C, Duration)> {
+        match self.should_reconnect_again() {
+            Some(delay) => Loop::Continue((self, network_request_stream, command_stream, delay)),
+            None => Loop::Break(()),
+        }
+    }
 
-    fn handle_connection_success(&mut self) {
+    fn handle_connection_success(&mut self, session_present: bool) {
         self.connection_count += 1;
+        self.connected_at = Some(Instant::now());
+        self.failed_endpoints = 0;
+
+        let address = self.endpoints[self.endpoint_index].address();
+        self.notification_tx.send(Notification::Connected(session_present, address));
 
         if self.connection_count == 1 {
-            let connection_tx = self.connection_tx.take().unwrap();
-            connection_tx.send(Ok(())).unwrap();
+            if let Some(connection_tx) = self.connection_tx.take() {
+                connection_tx.send(Ok(())).unwrap();
+            }
         }
     }
 
-    fn handle_connection_error(&mut self, error: timeout::Error<ConnectError>) {
+    /// Returns the error if the event loop should give up because the initial
+    /// connection failed
+    fn handle_connection_error(&mut self, error: ConnectError) -> Option<ConnectError> {
         self.connection_count += 1;
+        if self.connection_count != 1 {
+            return None;
+        }
+
+        match self.mqttoptions.reconnect_opts() {
+            ReconnectOptions::AfterFirstSuccess(_) | ReconnectOptions::Never => Some(error),
+            ReconnectOptions::Always(_) | ReconnectOptions::Backoff(_) => None,
+        }
+    }
+
+    /// Moves to the next endpoint after a connection failure. Returns `true` if there
+    /// are endpoints which weren't tried since the last successful connection. Those
+    /// are tried immediately without waiting for the reconnection delay
+    fn failover(&mut self) -> bool {
+        self.endpoint_index = (self.endpoint_index + 1) % self.endpoints.len();
+        self.failed_endpoints += 1;
 
-        let error = match error.into_inner() {
-            Some(e) => Err(e),
-            None => Err(ConnectError::Timeout),
+        if self.failed_endpoints < self.endpoints.len() {
+            debug!("Failing over to {:?}", self.endpoints[self.endpoint_index].address());
+            return true;
+        }
+
+        self.failed_endpoints = 0;
+        false
+    }
+
+    /// Resets reconnection attempts if the connection was up for long enough. Backoff
+    /// delays start again from the initial delay after a stable connection
+    fn handle_disconnection(&mut self) {
+        let stable_after = match self.mqttoptions.reconnect_opts() {
+            ReconnectOptions::Backoff(backoff) => backoff.stable_after(),
+            _ => Duration::from_secs(0),
         };
 
-        if self.connection_count == 1 {
-            match self.mqttoptions.reconnect_opts() {
-                ReconnectOptions::AfterFirstSuccess(_) => {
-                    let connection_tx = self.connection_tx.take().unwrap();
-                    connection_tx.send(error).unwrap();
-                }
-                ReconnectOptions::Never => {
-                    let connection_tx = self.connection_tx.take().unwrap();
-                    connection_tx.send(error).unwrap();
-                }
-                ReconnectOptions::Always(_) => (),
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= stable_after {
+                self.reconnect_attempts = 0;
+                self.last_reconnect_delay = Duration::from_secs(0);
             }
         }
     }
 
-    /// Resolves dns with blocking API and composes a future
-    /// which makes a new tcp or tls connection to the broker.
+    /// Notifies the user about the next reconnection attempt and returns the delay
+    /// before the attempt. Returns `None` if the event loop shouldn't reconnect anymore
+    fn should_reconnect_again(&mut self) -> Option<Duration> {
+        let delay = match self.mqttoptions.reconnect_opts() {
+            ReconnectOptions::AfterFirstSuccess(time) => Duration::from_secs(time),
+            ReconnectOptions::Always(time) => Duration::from_secs(time),
+            ReconnectOptions::Backoff(backoff) => {
+                if let Some(max_attempts) = backoff.max_attempts() {
+                    if self.reconnect_attempts >= max_attempts {
+                        error!("Giving up after {} reconnection attempts", self.reconnect_attempts);
+                        let notification = Notification::ReconnectAttemptsExhausted(self.reconnect_attempts);
+                        self.notification_tx.send(notification);
+                        return None;
+                    }
+                }
+
+                let delay = backoff.delay(self.reconnect_attempts, self.last_reconnect_delay);
+                self.last_reconnect_delay = delay;
+                delay
+            }
+            ReconnectOptions::Never => return None,
+        };
+
+        self.reconnect_attempts += 1;
+        let notification = Notification::Reconnecting(self.reconnect_attempts, delay);
+        self.notification_tx.send(notification);
+        Some(delay)
+    }
+
+    /// Composes a future which resolves dns on a separate thread
+    /// and makes a new tcp or tls connection to the broker.
     /// Note that this doesn't actual connect to the broker
     fn tcp_connect_future(&self) -> impl Future<Item = MqttFramed, Error = ConnectError> {
-        let (host, port) = self.mqttoptions.broker_address();
-        let connection_method = self.mqttoptions.connection_method();
-        let builder = NetworkStream::builder();
+        let (provided_ca, provided_identity) = match self.provided_tls_credentials() {
+            Ok(credentials) => credentials,
+            Err(e) => return future::Either::B(future::err(e)),
+        };
+
+        let endpoint = &self.endpoints[self.endpoint_index];
+        let (host, port) = endpoint.address();
+        let connection_method = endpoint.connection_method().unwrap_or_else(|| self.mqttoptions.connection_method());
+        let builder = NetworkStream::builder().set_max_incoming_packet_size(self.mqttoptions.max_incoming_packet_size())
+                                              .set_resolver(self.mqttoptions.resolver());
+
+        let builder = match self.mqttoptions.proxy() {
+            Some(proxy) => builder.set_proxy(proxy),
+            None => builder,
+        };
+
+        let builder = match self.mqttoptions.tls_server_name() {
+            Some(name) => builder.set_server_name(&name),
+            None => builder,
+        };
 
+        let builder = builder.set_alpn_protocols(self.mqttoptions.alpn_protocols())
+                             .set_server_verification(self.mqttoptions.server_verification());
+
+        // rotated credentials replace the ones of tls connection methods
         let builder = match connection_method {
-            ConnectionMethod::Tls(ca, Some((cert, key))) => builder.add_certificate_authority(&ca).add_client_auth(&cert, &key),
-            ConnectionMethod::Tls(ca, None) => builder.add_certificate_authority(&ca),
+            ConnectionMethod::Tls(ca, identity) => {
+                add_tls(builder, &provided_ca.unwrap_or(ca), provided_identity.or(identity), &self.mqttoptions)
+            }
             ConnectionMethod::Tcp => builder,
+            ConnectionMethod::WebSocket(path, headers) => {
+                let path = self.websocket_path(&host, port, &path);
+                builder.set_websocket(&path, headers)
+            }
+            ConnectionMethod::SecureWebSocket(ca, identity, path, headers) => {
+                let path = self.websocket_path(&host, port, &path);
+                let builder = add_tls(builder, &provided_ca.unwrap_or(ca), provided_identity.or(identity), &self.mqttoptions);
+                builder.set_websocket(&path, headers)
+            }
+            #[cfg(feature = "nativetls")]
+            ConnectionMethod::TlsPkcs12(ca, pkcs12, password) => {
+                let builder = add_certificate_authority(builder, &provided_ca.unwrap_or(ca), &self.mqttoptions);
+                match provided_identity {
+                    Some((cert, key)) => builder.add_client_auth(&cert, &key),
+                    None => builder.add_pkcs12_identity(&pkcs12, &password),
+                }
+            }
+            #[cfg(unix)]
+            ConnectionMethod::Unix(path) => builder.set_unix_path(path),
         };
 
-        builder.connect(&host, port)
+        future::Either::A(builder.connect(&host, port))
+    }
+
+    /// Websocket path with a freshly signed query when authenticating with aws sigv4
+    fn websocket_path(&self, host: &str, port: u16, path: &str) -> String {
+        match self.mqttoptions.security_opts() {
+            SecurityOptions::AwsSigV4(credentials) => {
+                // signed host has to match the host header of the upgrade request
+                let host = websocket::host_header(host, port);
+                cloud::aws_sigv4_path(&credentials, &host, path, cloud::unix_time())
+            }
+            _ => path.to_owned(),
+        }
+    }
+
+    /// Certificate authority and client identity from the credentials provider
+    fn provided_tls_credentials(&self) -> Result<(Option<Vec<u8>>, Option<(Vec<u8>, Vec<u8>)>), ConnectError> {
+        let provider = match self.mqttoptions.credentials_provider() {
+            Some(provider) => provider,
+            None => return Ok((None, None)),
+        };
+
+        let ca = provider.certificate_authority();
+        let identity = provider.client_identity();
+        match (ca, identity) {
+            (Ok(ca), Ok(identity)) => Ok((ca, identity)),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to get tls credentials. Error = {:?}", e);
+                Err(ConnectError::CredentialsUnavailable)
+            }
+        }
     }
 
     /// Composes a new future which is a combination of tcp connect + mqtt handshake
-    fn mqtt_connect(&self) -> impl Future<Item = MqttFramed, Error = ConnectError> {
+    /// Resolves with the framed network stream and the session present flag of the connack
+    fn mqtt_connect(&self) -> impl ConnackFuture {
         let mqtt_state = self.mqtt_state.clone();
         let tcp_connect_future = self.tcp_connect_future();
-        let connect_packet = self.mqtt_state.borrow_mut().handle_outgoing_connect().unwrap();
+        // packet is created on every attempt to pick up rotated credentials
+        let connect_packet = self.mqtt_state.lock().unwrap().handle_outgoing_connect(Instant::now());
 
-        tcp_connect_future.and_then(move |framed| {
-                              let packet = Packet::Connect(connect_packet);
-                              framed.send(packet).map_err(ConnectError::Io)
-                          })
-                          .and_then(|framed| framed.into_future().map_err(|(err, _framed)| ConnectError::Io(err)))
-                          .and_then(move |(response, framed)| {
-                              debug!("Mqtt connect response = {:?}", response);
-                              let mut mqtt_state = mqtt_state.borrow_mut();
-                              check_and_validate_connack(response, framed, &mut mqtt_state)
-                          })
+        future::result(connect_packet).and_then(move |connect_packet| {
+                                          tcp_connect_future.and_then(move |framed| {
+                                                                let packet = Packet::Connect(connect_packet);
+                                                                framed.send(packet).map_err(ConnectError::Io)
+                                                            })
+                                      })
+                                      .and_then(|framed| framed.into_future().map_err(|(err, _framed)| ConnectError::Io(err)))
+                                      .and_then(move |(response, framed)| {
+                                          debug!("Mqtt connect response = {:?}", response);
+                                          let mut mqtt_state = mqtt_state.lock().unwrap();
+                                          check_and_validate_connack(response, framed, &mut mqtt_state)
+                                      })
     }
 
     /// Handles all incoming network packets (including sending notifications to user over crossbeam
@@ -222,40 +480,56 @@ impl Connection {
         let keep_alive = self.mqttoptions.keep_alive();
         let network_stream = Timeout::new(network_stream, keep_alive);
 
-        // TODO: prevent this clone?
-        // cloning crossbeam channel sender every time is a problem according to docs
         let notification_tx = self.notification_tx.clone();
         let network_stream = network_stream.map_err(NetworkError::TimeOut)
                                            .and_then(move |packet| {
                                                debug!("Incoming packet = {:?}", packet_info(&packet));
-                                               let reply = mqtt_state_in.borrow_mut().handle_incoming_mqtt_packet(packet);
+                                               let mut mqtt_state = mqtt_state_in.lock().unwrap();
+                                               let was_full = mqtt_state.is_inflight_full();
+                                               let reply = mqtt_state.handle_incoming_mqtt_packet(packet, Instant::now());
+
+                                               // publishes are held back while the in-flight window is
+                                               // full. wake the event loop to send them
+                                               if was_full && !mqtt_state.is_inflight_full() {
+                                                   task::current().notify();
+                                               }
                                                future::result(reply)
                                            })
                                            .and_then(move |(notification, reply)| {
-                                               let notification_tx = notification_tx.clone();
-                                               handle_notification(notification, &notification_tx);
+                                               notification_tx.send(notification);
                                                future::ok(reply)
                                            })
                                            .or_else(move |e| {
-                                               let mut mqtt_state_out = mqtt_state_out.borrow_mut();
+                                               let mut mqtt_state_out = mqtt_state_out.lock().unwrap();
                                                handle_stream_error(e, &mut mqtt_state_out)
                                            })
                                            .filter(|reply| should_forward_packet(reply))
                                            .and_then(move |packet| future::ok(packet.into()));
 
+        // primary endpoint is retried after a while when connected to a fallback
+        let primary_retry_interval = match self.endpoint_index {
+            0 => None,
+            _ => self.mqttoptions.primary_retry_interval(),
+        };
+
+        let failback_at = primary_retry_interval.map(|interval| Instant::now() + interval);
+        let token_refresh_at = self.mqtt_state.lock().unwrap().token_refresh_at();
+
         network_stream.chain(stream::once(Err(NetworkError::NetworkStreamClosed)))
+                      .select(disconnect_at(failback_at, || NetworkError::ReturnToPrimary))
+                      .select(disconnect_at(token_refresh_at, || NetworkError::TokenExpiring))
     }
 
     fn network_request_stream(&mut self, previous_request_stream: impl PacketStream) -> Prepend<impl PacketStream> {
         let mqtt_state = self.mqtt_state.clone();
-        let last_session_publishes = mqtt_state.borrow_mut().handle_reconnection();
+        let last_session_publishes = mqtt_state.lock().unwrap().handle_reconnection();
         previous_request_stream.prepend(last_session_publishes)
     }
 
     fn merge_network_request_stream(&mut self,
                                     previous_request_stream: &mut Prepend<impl PacketStream>) {
         let mqtt_state = self.mqtt_state.clone();
-        let last_session_publishes = mqtt_state.borrow_mut().handle_reconnection();
+        let last_session_publishes = mqtt_state.lock().unwrap().handle_reconnection();
         previous_request_stream.merge_session(last_session_publishes);
     }
 
@@ -265,22 +539,49 @@ impl Connection {
     /// to user request stream to ensure that they are handled first. This cleanly handles last
     /// session stray (even if disconnect happens while sending last session data)because we always
     /// get back this stream from reactor after disconnection.
+    /// Requests aren't pulled from the channel while `max_inflight` publishes are
+    /// waiting for acks. Incoming acks wake the event loop to pull them again
+    // Publishes read while the in-flight window is full are held back (in order) till
+    // acks make room. Other requests keep flowing. Held publishes are bounded by the
+    // window so that the request channel still applies backpressure to the user
     fn request_stream(&mut self, request: mpsc::Receiver<Request>) -> impl PacketStream {
         let mqtt_state = self.mqtt_state.clone();
+        let mut held = VecDeque::new();
 
-        let request_stream = request.map_err(|e| {
-                                               error!("User request error = {:?}", e);
-                                               NetworkError::Blah
-                                           })
-                                           .and_then(move |userrequest| {
-                                               let mut mqtt_state = mqtt_state.borrow_mut();
-                                               validate_userrequest(userrequest, &mut mqtt_state)
-                                           });
+        let mut requests = request.map_err(|e| {
+                                      error!("User request error = {:?}", e);
+                                      NetworkError::Blah
+                                  });
 
-        let mqtt_state = self.mqtt_state.clone();
-        request_stream.and_then(move |packet: Packet| {
-            let o = mqtt_state.borrow_mut().handle_outgoing_mqtt_packet(packet);
-            future::result(o)
+        stream::poll_fn(move || {
+            let mut mqtt_state = mqtt_state.lock().unwrap();
+            if !held.is_empty() && !mqtt_state.is_inflight_full() {
+                let userrequest = held.pop_front().unwrap();
+                return validate_userrequest(userrequest, &mut mqtt_state).poll().map(|packet| packet.map(Some));
+            }
+
+            while held.len() < mqtt_state.opts.max_inflight() {
+                let userrequest = match requests.poll()? {
+                    Async::Ready(Some(userrequest)) => userrequest,
+                    Async::Ready(None) if held.is_empty() => return Ok(Async::Ready(None)),
+                    Async::Ready(None) | Async::NotReady => return Ok(Async::NotReady),
+                };
+
+                let is_publish = match userrequest {
+                    Request::Publish(_) | Request::TrackedPublish(..) => true,
+                    _ => false,
+                };
+
+                // qos0 publishes wait behind held ones too to keep the publish order
+                if is_publish && (!held.is_empty() || mqtt_state.is_inflight_full()) {
+                    held.push_back(userrequest);
+                    continue;
+                }
+
+                return validate_userrequest(userrequest, &mut mqtt_state).poll().map(|packet| packet.map(Some));
+            }
+
+            Ok(Async::NotReady)
         })
     }
 
@@ -298,17 +599,170 @@ fn validate_userrequest(userrequest: Request, mqtt_state: &mut MqttState) -> imp
             mqtt_state.opts = mqttoptions;
             future::err(NetworkError::UserReconnect)
         }
-        _ => future::ok(userrequest.into()),
+        Request::TrackedPublish(publish, delivery_tx) => {
+            let publish = mqtt_state.handle_outgoing_tracked_publish(publish, delivery_tx);
+            future::result(publish.map(Packet::Publish))
+        }
+        _ => future::result(mqtt_state.handle_outgoing_mqtt_packet(userrequest.into(), Instant::now())),
     }
 }
 
-fn handle_notification(notification: Notification, notification_tx: &Sender<Notification>) {
-    match notification {
-        Notification::None => (),
-        _ => match notification_tx.try_send(notification) {
-            Ok(()) => (),
-            Err(e) => error!("Notification send failed. Error = {:?}", e),
-        },
+/// Event loop's ends of the channels to the user
+struct EventLoopChannels {
+    notification_tx: Sender<Notification>,
+    oldest_rx: Option<crossbeam_channel::Receiver<Notification>>,
+    overflow: NotificationOverflow,
+    notification_task: Arc<AtomicTask>,
+    request_rx: Receiver<Request>,
+    command_rx: Receiver<Command>,
+}
+
+impl EventLoopChannels {
+    fn new(mqttoptions: &MqttOptions) -> (UserHandle, EventLoopChannels) {
+        let overflow = mqttoptions.notification_overflow();
+        let (notification_tx, notification_rx) = match overflow {
+            NotificationOverflow::Unbounded => crossbeam_channel::unbounded(),
+            _ => crossbeam_channel::bounded(mqttoptions.notification_channel_capacity()),
+        };
+
+        // event loop needs the receiving end to throw away the oldest notifications
+        let oldest_rx = match overflow {
+            NotificationOverflow::DropOldest => Some(notification_rx.clone()),
+            _ => None,
+        };
+        let (request_tx, request_rx) = mpsc::channel::<Request>(10);
+        let (command_tx, command_rx) = mpsc::channel::<Command>(5);
+        let notification_task = Arc::new(AtomicTask::new());
+
+        let user_handle = UserHandle { request_tx,
+                                       command_tx,
+                                       notification_rx,
+                                       notification_task: notification_task.clone() };
+        let channels = EventLoopChannels { notification_tx,
+                                           oldest_rx,
+                                           overflow,
+                                           notification_task,
+                                           request_rx,
+                                           command_rx };
+
+        (user_handle, channels)
+    }
+
+    /// Splits the channels of the event loop and builds its notification sender
+    fn split(self) -> (NotificationSender, Receiver<Request>, Receiver<Command>) {
+        let notification_tx =
+            NotificationSender::new(self.notification_tx, self.oldest_rx, self.overflow, self.notification_task);
+        (notification_tx, self.request_rx, self.command_rx)
+    }
+}
+
+/// Sends notifications to the user as per the overflow policy in `MqttOptions`.
+/// Notifications which are dropped because the user isn't keeping up are
+/// counted and reported with `Notification::Dropped` when there is space again
+#[derive(Clone)]
+struct NotificationSender {
+    tx: Sender<Notification>,
+    // only for `DropOldest`. Holding a receiver keeps the channel alive even
+    // when the user drops theirs
+    oldest_rx: Option<crossbeam_channel::Receiver<Notification>>,
+    overflow: NotificationOverflow,
+    dropped: Arc<Mutex<u64>>,
+    // declared after `tx` to wake the notification stream after the channel closes
+    waker: NotificationWaker,
+}
+
+impl NotificationSender {
+    fn new(tx: Sender<Notification>,
+           oldest_rx: Option<crossbeam_channel::Receiver<Notification>>,
+           overflow: NotificationOverflow,
+           task: Arc<AtomicTask>)
+           -> NotificationSender {
+        NotificationSender { tx,
+                             oldest_rx,
+                             overflow,
+                             dropped: Arc::new(Mutex::new(0)),
+                             waker: NotificationWaker(task) }
+    }
+
+    fn send(&self, notification: Notification) {
+        if let Notification::None = notification {
+            return;
+        }
+
+        self.try_send(notification);
+        self.waker.0.notify();
+    }
+
+    fn try_send(&self, notification: Notification) {
+        match self.overflow {
+            // blocks the event loop (and hence network reads) till the user catches up
+            NotificationOverflow::Block | NotificationOverflow::Unbounded => {
+                if let Err(e) = self.tx.send(notification) {
+                    error!("Notification send failed. Error = {:?}", e);
+                }
+            }
+            NotificationOverflow::DropNewest => {
+                self.report_dropped();
+                match self.tx.try_send(notification) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(notification)) => {
+                        error!("Notification channel full. Dropping {:?}", notification);
+                        *self.dropped.lock().unwrap() += 1;
+                    }
+                    Err(e) => error!("Notification send failed. Error = {:?}", e),
+                }
+            }
+            NotificationOverflow::DropOldest => {
+                self.report_dropped();
+                let mut notification = notification;
+                loop {
+                    match self.tx.try_send(notification) {
+                        Ok(()) => break,
+                        Err(TrySendError::Full(n)) => {
+                            notification = n;
+                            self.drop_oldest();
+                        }
+                        Err(e) => {
+                            error!("Notification send failed. Error = {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn drop_oldest(&self) {
+        let oldest_rx = self.oldest_rx.as_ref().expect("Drop oldest policy without receiver");
+        let dropped = match oldest_rx.try_recv() {
+            // don't lose the count of previous drops
+            Ok(Notification::Dropped(count)) => count,
+            Ok(notification) => {
+                error!("Notification channel full. Dropping {:?}", notification);
+                1
+            }
+            Err(_) => 0,
+        };
+
+        *self.dropped.lock().unwrap() += dropped;
+    }
+
+    fn report_dropped(&self) {
+        let mut dropped = self.dropped.lock().unwrap();
+        if *dropped > 0 && self.tx.try_send(Notification::Dropped(*dropped)).is_ok() {
+            *dropped = 0;
+        }
+    }
+}
+
+/// Wakes the task polling the notification stream of the async client. Also wakes
+/// it when dropped so that the stream sees the end of the channel
+#[derive(Clone)]
+struct NotificationWaker(Arc<AtomicTask>);
+
+impl Drop for NotificationWaker {
+    fn drop(&mut self) {
+        self.0.notify();
     }
 }
 
@@ -316,7 +770,7 @@ fn handle_stream_error(error: NetworkError, mqtt_state: &mut MqttState) -> impl
     match error {
         NetworkError::TimeOut(ref e) if e.is_elapsed() => {
             let ping = Packet::Pingreq;
-            match mqtt_state.handle_outgoing_mqtt_packet(ping) {
+            match mqtt_state.handle_outgoing_mqtt_packet(ping, Instant::now()) {
                 Ok(_) => future::ok(Request::Ping),
                 Err(e) => future::err(e),
             }
@@ -330,17 +784,67 @@ fn handle_stream_error(error: NetworkError, mqtt_state: &mut MqttState) -> impl
 
 /// Checks if incoming packet is mqtt connack packet. Useful after mqtt
 /// connect when we are waiting for connack but not any other packet.
-fn check_and_validate_connack(packet: Option<Packet>, framed: MqttFramed, mqtt_state: &mut MqttState) -> impl FramedFuture {
+fn check_and_validate_connack(packet: Option<Packet>, framed: MqttFramed, mqtt_state: &mut MqttState) -> impl ConnackFuture {
     match packet {
-        Some(Packet::Connack(connack)) => match mqtt_state.handle_incoming_connack(connack) {
-            Err(err) => future::err(err),
-            _ => future::ok(framed),
-        },
+        Some(Packet::Connack(connack)) => {
+            let session_present = connack.session_present;
+            match mqtt_state.handle_incoming_connack(connack, Instant::now()) {
+                Err(err) => future::err(err),
+                _ => future::ok((framed, session_present)),
+            }
+        }
         Some(packet) => future::err(ConnectError::NotConnackPacket(packet)),
         None => future::err(ConnectError::NoResponse),
     }
 }
 
+/// Errors out at `deadline` to force a reconnection (e.g. to go back to the primary
+/// endpoint or to refresh the token). Never ends when there is no deadline
+fn disconnect_at(deadline: Option<Instant>, error: fn() -> NetworkError) -> impl PacketStream {
+    let mut delay = deadline.map(Delay::new);
+
+    stream::poll_fn(move || match delay {
+        Some(ref mut delay) => match delay.poll() {
+            Ok(Async::Ready(())) => Err(error()),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NetworkError::Timer(e)),
+        },
+        None => Ok(Async::NotReady),
+    })
+}
+
+/// Enables tls with the certificate authority and, optionally, the system's root
+/// certificates. Empty certificate authority is skipped when the system's root
+/// certificates are trusted or the server verification doesn't use it (e.g pins).
+/// It fails the connection as an invalid certificate otherwise
+fn add_certificate_authority(builder: NetworkStreamBuilder, ca: &[u8], opts: &MqttOptions) -> NetworkStreamBuilder {
+    let system_roots = opts.use_system_roots();
+    let builder = if system_roots { builder.use_system_roots() } else { builder };
+
+    if !ca.is_empty() {
+        builder.add_certificate_authority(ca)
+    } else if system_roots {
+        builder
+    } else if !opts.server_verification().uses_certificate_authority() {
+        builder.enable_tls()
+    } else {
+        builder.add_certificate_authority(ca)
+    }
+}
+
+/// Enables tls with the certificate authority and, optionally, a client identity
+fn add_tls(builder: NetworkStreamBuilder,
+           ca: &[u8],
+           identity: Option<(Vec<u8>, Vec<u8>)>,
+           opts: &MqttOptions)
+           -> NetworkStreamBuilder {
+    let builder = add_certificate_authority(builder, ca, opts);
+    match identity {
+        Some((cert, key)) => builder.add_client_auth(&cert, &key),
+        None => builder,
+    }
+}
+
 fn should_forward_packet(reply: &Request) -> bool {
     match reply {
         Request::None => false,
@@ -363,22 +867,6 @@ fn packet_info(packet: &Packet) -> String {
     }
 }
 
-fn should_reconnect_again(reconnect_options: ReconnectOptions) -> bool {
-    match reconnect_options {
-        ReconnectOptions::AfterFirstSuccess(time) => {
-            let time = Duration::from_secs(time);
-            thread::sleep(time);
-            true
-        }
-        ReconnectOptions::Always(time) => {
-            let time = Duration::from_secs(time);
-            thread::sleep(time);
-            true
-        }
-        ReconnectOptions::Never => false
-    }
-}
-
 impl From<Request> for Packet {
     fn from(item: Request) -> Self {
         match item {
@@ -390,6 +878,7 @@ impl From<Request> for Packet {
             Request::Ping => Packet::Pingreq,
             Request::Disconnect => Packet::Disconnect,
             Request::Subscribe(subscribe) => Packet::Subscribe(subscribe),
+            Request::Unsubscribe(unsubscribe) => Packet::Unsubscribe(unsubscribe),
             _ => unimplemented!(),
         }
     }
@@ -409,5 +898,472 @@ impl<T> PacketFuture for T where T: Future<Item = Packet, Error = NetworkError>
 trait RequestFuture: Future<Item = Request, Error = NetworkError> {}
 impl<T> RequestFuture for T where T: Future<Item = Request, Error = NetworkError> {}
 
-trait FramedFuture: Future<Item = MqttFramed, Error = ConnectError> {}
-impl<T> FramedFuture for T where T: Future<Item = MqttFramed, Error = ConnectError> {}
\ No newline at end of file
+trait ConnackFuture: Future<Item = (MqttFramed, bool), Error = ConnectError> {}
+impl<T> ConnackFuture for T where T: Future<Item = (MqttFramed, bool), Error = ConnectError> {}
+
+#[cfg(test)]
+mod test {
+    use super::{Connection, NotificationSender};
+    use client::{credentials::CredentialsProvider, session::Session, MqttClient, Notification};
+    use crossbeam_channel;
+    use futures::{task::AtomicTask, Future};
+    use mqtt311::{PacketIdentifier, QoS};
+    use error::{ConnectError, NetworkError};
+    use mqttoptions::{ConnectionMethod, Endpoint, MqttOptions, NotificationOverflow, ReconnectOptions};
+    use std::{
+        io::{self, Read, Write},
+        net::TcpListener,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+    use tokio::runtime::{current_thread, Runtime};
+
+    /// Credentials which the test changes between connection attempts
+    #[derive(Debug, Default)]
+    struct RotatingCredentials {
+        ca: Mutex<Option<Vec<u8>>>,
+        identity: Mutex<Option<(Vec<u8>, Vec<u8>)>>,
+    }
+
+    impl CredentialsProvider for RotatingCredentials {
+        fn certificate_authority(&self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.ca.lock().unwrap().clone())
+        }
+
+        fn client_identity(&self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+            Ok(self.identity.lock().unwrap().clone())
+        }
+    }
+
+    fn read_packet<R: Read>(stream: &mut R) -> Vec<u8> {
+        let mut packet = vec![0; 2];
+        stream.read_exact(&mut packet).unwrap();
+        let mut body = vec![0; packet[1] as usize];
+        stream.read_exact(&mut body).unwrap();
+        packet.extend(body);
+        packet
+    }
+
+    #[test]
+    fn drop_newest_should_report_dropped_notifications() {
+        let (tx, rx) = crossbeam_channel::bounded(2);
+        let notification_tx = NotificationSender::new(tx, None, NotificationOverflow::DropNewest, Arc::new(AtomicTask::new()));
+
+        for i in 1..5 {
+            notification_tx.send(Notification::PubAck(PacketIdentifier(i)));
+        }
+
+        match rx.try_recv().unwrap() {
+            Notification::PubAck(pkid) => assert_eq!(pkid, PacketIdentifier(1)),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        notification_tx.send(Notification::PubAck(PacketIdentifier(5)));
+
+        match rx.try_recv().unwrap() {
+            Notification::PubAck(pkid) => assert_eq!(pkid, PacketIdentifier(2)),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        match rx.try_recv().unwrap() {
+            Notification::Dropped(count) => assert_eq!(count, 2),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+    }
+
+    #[test]
+    fn drop_oldest_should_keep_latest_notifications() {
+        let (tx, rx) = crossbeam_channel::bounded(2);
+        let task = Arc::new(AtomicTask::new());
+        let notification_tx = NotificationSender::new(tx, Some(rx.clone()), NotificationOverflow::DropOldest, task);
+
+        for i in 1..5 {
+            notification_tx.send(Notification::PubAck(PacketIdentifier(i)));
+        }
+
+        match rx.try_recv().unwrap() {
+            Notification::PubAck(pkid) => assert_eq!(pkid, PacketIdentifier(3)),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        // drop report takes the free slot and 5 pushes out 4
+        notification_tx.send(Notification::PubAck(PacketIdentifier(5)));
+
+        match rx.try_recv().unwrap() {
+            Notification::Dropped(count) => assert_eq!(count, 2),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        match rx.try_recv().unwrap() {
+            Notification::PubAck(pkid) => assert_eq!(pkid, PacketIdentifier(5)),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        assert_eq!(*notification_tx.dropped.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn eventloops_should_share_the_callers_executor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut header = [0; 2];
+                stream.read_exact(&mut header).unwrap();
+                let mut connect = vec![0; header[1] as usize];
+                stream.read_exact(&mut connect).unwrap();
+
+                // connack and close. event loops stop as they don't reconnect
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            }
+        });
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let mut handles = Vec::new();
+        for id in &["client-1", "client-2"] {
+            let opts = MqttOptions::new(*id, "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+            let (handle, eventloop) = Connection::eventloop(opts);
+            rt.spawn(eventloop.map_err(|e| panic!("Event loop failed. Error = {:?}", e)));
+            handles.push(handle);
+        }
+
+        rt.run().unwrap();
+
+        for handle in handles {
+            match handle.notification_rx.try_recv().unwrap() {
+                Notification::Connected(false, (host, p)) => assert_eq!((host.as_str(), p), ("127.0.0.1", port)),
+                n => panic!("Invalid notification: {:?}", n),
+            }
+        }
+    }
+
+    #[test]
+    fn eventloops_should_share_a_threadpool_runtime() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(8) {
+                let mut stream = stream.unwrap();
+                let mut header = [0; 2];
+                stream.read_exact(&mut header).unwrap();
+                let mut connect = vec![0; header[1] as usize];
+                stream.read_exact(&mut connect).unwrap();
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            }
+        });
+
+        let rt = Runtime::new().unwrap();
+        let mut clients = Vec::new();
+        for i in 0..8 {
+            let opts = MqttOptions::new(format!("client-{}", i), "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+            let (client, notification_rx, eventloop) = MqttClient::eventloop(opts);
+            rt.executor().spawn(eventloop.map_err(|e| panic!("Event loop failed. Error = {:?}", e)));
+            clients.push((client, notification_rx));
+        }
+
+        rt.shutdown_on_idle().wait().unwrap();
+
+        for (_client, notification_rx) in clients {
+            match notification_rx.try_recv().unwrap() {
+                Notification::Connected(false, (host, p)) => assert_eq!((host.as_str(), p), ("127.0.0.1", port)),
+                n => panic!("Invalid notification: {:?}", n),
+            }
+        }
+    }
+
+    #[test]
+    fn eventloop_should_fail_if_initial_connection_fails() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+        let (_handle, eventloop) = Connection::eventloop(opts);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        match rt.block_on(eventloop) {
+            Err(_) => (),
+            v => panic!("Should fail the initial connection. Got = {:?}", v),
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn every_connection_attempt_should_use_the_provided_tls_credentials() {
+        use futures::Stream;
+        use mqtt311::Packet;
+        use tokio_rustls::rustls::{
+            internal::pemfile,
+            AllowAnyAuthenticatedClient,
+            RootCertStore,
+            ServerConfig,
+            ServerSession,
+            Stream as TlsStream,
+        };
+
+        let ca = include_bytes!("../../tests/tlsfiles/ca.cert.pem").to_vec();
+        let other = include_bytes!("../../tests/tlsfiles/other.cert.der").to_vec();
+        let client_cert = include_bytes!("../../tests/tlsfiles/client.cert.pem").to_vec();
+        let client_key = include_bytes!("../../tests/tlsfiles/client.key.pem").to_vec();
+        let server_cert = include_bytes!("../../tests/tlsfiles/server.cert.pem");
+        let server_key = include_bytes!("../../tests/tlsfiles/server.key.pem");
+
+        // tls server which only accepts clients with a certificate of the test ca
+        let mut roots = RootCertStore::empty();
+        roots.add_pem_file(&mut &ca[..]).unwrap();
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+        let certs = pemfile::certs(&mut &server_cert[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &server_key[..]).unwrap().remove(0);
+        config.set_single_cert(certs, key).unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(3) {
+                let mut stream = stream.unwrap();
+                let mut session = ServerSession::new(&config);
+                let mut stream = TlsStream::new(&mut session, &mut stream);
+                // pingresp
+                let _ = stream.write_all(&[0xD0, 0x00]).and_then(|_| stream.flush());
+            }
+        });
+
+        let credentials = Arc::new(RotatingCredentials::default());
+        *credentials.ca.lock().unwrap() = Some(ca);
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_connection_method(ConnectionMethod::Tls(other.clone(), None))
+                                                                .set_tls_server_name("localhost")
+                                                                .set_credentials_provider(credentials.clone());
+
+        let (tx, _rx) = crossbeam_channel::bounded(10);
+        let notification_tx = NotificationSender::new(tx, None, NotificationOverflow::DropNewest, Arc::new(AtomicTask::new()));
+        let connection = Connection::new(opts, Session::default(), notification_tx, None);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let mut connect = || {
+            let future = connection.tcp_connect_future()
+                                   .map_err(|_| ())
+                                   .and_then(|framed| framed.into_future().map_err(|_| ()));
+            match rt.block_on(future) {
+                Ok((packet, _framed)) => packet,
+                Err(()) => None,
+            }
+        };
+
+        // server rejects clients without identity
+        assert_ne!(connect(), Some(Packet::Pingresp));
+
+        *credentials.identity.lock().unwrap() = Some((client_cert, client_key));
+        assert_eq!(connect(), Some(Packet::Pingresp));
+
+        // rotated out certificate authority isn't trusted anymore
+        *credentials.ca.lock().unwrap() = Some(other);
+        assert_ne!(connect(), Some(Packet::Pingresp));
+    }
+
+    #[test]
+    fn eventloop_should_fail_over_and_return_to_primary() {
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fallback = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary_port = primary.local_addr().unwrap().port();
+        let fallback_port = fallback.local_addr().unwrap().port();
+
+        // refuses the first connection as not authorized and accepts the second
+        thread::spawn(move || {
+            let connacks: [&[u8]; 2] = [&[0x20, 0x02, 0x00, 0x05], &[0x20, 0x02, 0x00, 0x00]];
+            for connack in connacks.iter() {
+                let (mut stream, _) = primary.accept().unwrap();
+                read_packet(&mut stream);
+                stream.write_all(connack).unwrap();
+            }
+        });
+
+        // accepts and holds the connection till the client returns to the primary
+        thread::spawn(move || {
+            let (mut stream, _) = fallback.accept().unwrap();
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            let _ = stream.read(&mut [0u8; 2]);
+        });
+
+        let endpoints = vec![Endpoint::new("127.0.0.1", primary_port), Endpoint::new("127.0.0.1", fallback_port)];
+        let retry_interval = Some(Duration::from_millis(500));
+        let opts = MqttOptions::new("client", "127.0.0.1", primary_port).set_endpoints(endpoints)
+                                                                        .set_primary_retry_interval(retry_interval)
+                                                                        .set_reconnect_opts(ReconnectOptions::Never);
+        let (handle, eventloop) = Connection::eventloop(opts);
+
+        // event loop stops when the primary closes the second connection
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(eventloop).unwrap();
+
+        let notifications: Vec<Notification> = handle.notification_rx.try_iter().collect();
+        match notifications.as_slice() {
+            [Notification::ConnectionRefused(5),
+             Notification::Connected(false, (_, fallback)),
+             Notification::Disconnected(NetworkError::ReturnToPrimary),
+             Notification::Connected(false, (_, primary)),
+             Notification::Disconnected(_)] => assert_eq!((*fallback, *primary), (fallback_port, primary_port)),
+            n => panic!("Should fail over and return to primary. Got = {:?}", n),
+        }
+    }
+
+    #[test]
+    fn provided_certificate_authority_should_not_enable_tls_on_tcp_endpoints() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // plaintext connect. tls would start with a client hello
+            let connect = read_packet(&mut stream);
+            assert_eq!(connect[0], 0x10);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+        });
+
+        let credentials = RotatingCredentials::default();
+        *credentials.ca.lock().unwrap() = Some(include_bytes!("../../tests/tlsfiles/ca.cert.pem").to_vec());
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_credentials_provider(Arc::new(credentials))
+                                                                .set_reconnect_opts(ReconnectOptions::Never);
+        let (handle, eventloop) = Connection::eventloop(opts);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(eventloop).unwrap();
+        match handle.notification_rx.try_recv().unwrap() {
+            Notification::Connected(false, (_, p)) => assert_eq!(p, port),
+            n => panic!("Should connect over plain tcp. Got = {:?}", n),
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn pins_should_work_without_certificate_authority_on_ip_addresses() {
+        use client::pinning::sha256;
+        use mqttoptions::{Pin, ServerVerification};
+        use tokio_rustls::rustls::{internal::pemfile, NoClientAuth, ServerConfig, ServerSession, Stream as TlsStream};
+
+        let server_cert = include_bytes!("../../tests/tlsfiles/server.cert.pem");
+        let server_key = include_bytes!("../../tests/tlsfiles/server.key.pem");
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        let certs = pemfile::certs(&mut &server_cert[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &server_key[..]).unwrap().remove(0);
+        config.set_single_cert(certs, key).unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = ServerSession::new(&config);
+            let mut stream = TlsStream::new(&mut session, &mut stream);
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let pin = Pin::Certificate(sha256(include_bytes!("../../tests/tlsfiles/server.cert.der")));
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_connection_method(ConnectionMethod::Tls(Vec::new(), None))
+                                                                .set_server_verification(ServerVerification::Pins(vec![pin]))
+                                                                .set_reconnect_opts(ReconnectOptions::Never);
+        let (handle, eventloop) = Connection::eventloop(opts);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(eventloop).unwrap();
+        match handle.notification_rx.try_recv().unwrap() {
+            Notification::Connected(false, (_, p)) => assert_eq!(p, port),
+            n => panic!("Should connect with pinned certificate. Got = {:?}", n),
+        }
+    }
+
+    #[test]
+    fn aws_sigv4_should_sign_the_sent_host_header() {
+        use client::cloud;
+        use mqttoptions::{AwsCredentials, SecurityOptions};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (request_tx, request_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+
+            request_tx.send(String::from_utf8(request).unwrap()).unwrap();
+        });
+
+        let credentials = AwsCredentials::new("AKIDEXAMPLE", "secret", "us-east-1");
+        let connection_method = ConnectionMethod::WebSocket("/mqtt".to_owned(), Vec::new());
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_connection_method(connection_method)
+                                                                .set_security_opts(SecurityOptions::AwsSigV4(credentials.clone()))
+                                                                .set_reconnect_opts(ReconnectOptions::Never);
+        let (_handle, eventloop) = Connection::eventloop(opts);
+
+        let start = cloud::unix_time();
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let _ = rt.block_on(eventloop);
+        let end = cloud::unix_time();
+
+        let request = request_rx.recv().unwrap();
+        let path = request.split(' ').nth(1).unwrap();
+        let host = request.lines()
+                          .find(|line| line.starts_with("Host:"))
+                          .map(|line| line["Host:".len()..].trim())
+                          .unwrap();
+        assert_eq!(host, format!("127.0.0.1:{}", port));
+        assert!((start..=end).any(|now| cloud::aws_sigv4_path(&credentials, host, "/mqtt", now) == path));
+    }
+
+    #[test]
+    fn empty_certificate_authority_should_need_system_roots() {
+        let opts = MqttOptions::new("client", "127.0.0.1", 8883).set_connection_method(ConnectionMethod::Tls(Vec::new(), None))
+                                                                .set_reconnect_opts(ReconnectOptions::Never);
+        let (_handle, eventloop) = Connection::eventloop(opts);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        match rt.block_on(eventloop) {
+            Err(ConnectError::InvalidCertificate) => (),
+            v => panic!("Should throw invalid certificate error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn eventloop_should_hold_back_publishes_while_inflight_window_is_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (packet_tx, packet_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+            let first = read_packet(&mut stream);
+            // subscribe passes the held back publish
+            let subscribe = read_packet(&mut stream);
+
+            // second publish shouldn't arrive till the first one is acked
+            stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut byte = [0; 1];
+            let blocked = stream.read(&mut byte).is_err();
+
+            stream.set_read_timeout(None).unwrap();
+            stream.write_all(&[0x40, 0x02, 0x00, 0x01]).unwrap();
+            let second = read_packet(&mut stream);
+            packet_tx.send((first, subscribe, blocked, second)).unwrap();
+        });
+
+        let opts = MqttOptions::new("client", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never)
+                                                                .set_max_inflight(1);
+        let (mut client, _notifications) = MqttClient::start(opts).unwrap();
+        client.publish("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]).unwrap();
+        client.publish("hello/world", QoS::AtLeastOnce, vec![4, 5, 6]).unwrap();
+        client.subscribe("hello/world", QoS::AtLeastOnce).unwrap();
+
+        let (first, subscribe, blocked, second) = packet_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(&first[first.len() - 3..], &[1, 2, 3]);
+        assert_eq!(subscribe[0], 0x82);
+        assert!(blocked);
+        assert_eq!(&second[second.len() - 3..], &[4, 5, 6]);
+    }
+}
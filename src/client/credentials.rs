@@ -0,0 +1,101 @@
+use std::{
+    fmt,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Supplies credentials on every connection attempt so that rotated certificates
+/// and tokens are picked up when the client reconnects. Credentials which aren't
+/// supplied (`None`) fall back to the ones in `MqttOptions`. Certificate authority
+/// and client identity only apply to tls connection methods
+pub trait CredentialsProvider: fmt::Debug + Send + Sync {
+    /// Pem or der encoded certificate authority. Replaces the certificate authority
+    /// of the connection method. Trust is set up again on every connection attempt,
+    /// so a certificate authority which is rotated out isn't trusted anymore
+    fn certificate_authority(&self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Pem or der encoded client certificate and private key
+    fn client_identity(&self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(None)
+    }
+
+    /// Username and password of the mqtt connect packet
+    fn username_password(&self) -> io::Result<Option<(String, String)>> {
+        Ok(None)
+    }
+}
+
+/// Reads certificate files from disk on every connection attempt
+#[derive(Clone, Debug, Default)]
+pub struct FileCredentials {
+    certificate_authority: Option<PathBuf>,
+    client_identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl FileCredentials {
+    pub fn new() -> FileCredentials {
+        FileCredentials::default()
+    }
+
+    pub fn set_certificate_authority<P: AsRef<Path>>(mut self, ca: P) -> Self {
+        self.certificate_authority = Some(ca.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn set_client_identity<P: AsRef<Path>, Q: AsRef<Path>>(mut self, cert: P, key: Q) -> Self {
+        self.client_identity = Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+        self
+    }
+}
+
+impl CredentialsProvider for FileCredentials {
+    fn certificate_authority(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.certificate_authority {
+            Some(ref ca) => Ok(Some(fs::read(ca)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn client_identity(&self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.client_identity {
+            Some((ref cert, ref key)) => Ok(Some((fs::read(cert)?, fs::read(key)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CredentialsProvider, FileCredentials};
+    use std::{env, fs, process};
+
+    #[test]
+    fn file_credentials_should_be_read_on_every_call() {
+        let dir = env::temp_dir();
+        let ca = dir.join(format!("rumqtt-ca-{}.pem", process::id()));
+        let cert = dir.join(format!("rumqtt-cert-{}.pem", process::id()));
+        let key = dir.join(format!("rumqtt-key-{}.pem", process::id()));
+
+        let credentials = FileCredentials::new().set_certificate_authority(&ca).set_client_identity(&cert, &key);
+        assert!(credentials.certificate_authority().is_err());
+
+        fs::write(&ca, b"ca-1").unwrap();
+        fs::write(&cert, b"cert-1").unwrap();
+        fs::write(&key, b"key-1").unwrap();
+        assert_eq!(credentials.certificate_authority().unwrap(), Some(b"ca-1".to_vec()));
+        assert_eq!(credentials.client_identity().unwrap(), Some((b"cert-1".to_vec(), b"key-1".to_vec())));
+
+        // rotation
+        fs::write(&cert, b"cert-2").unwrap();
+        fs::write(&key, b"key-2").unwrap();
+        assert_eq!(credentials.client_identity().unwrap(), Some((b"cert-2".to_vec(), b"key-2".to_vec())));
+        assert_eq!(credentials.username_password().unwrap(), None);
+
+        for path in &[ca, cert, key] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
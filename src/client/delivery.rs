@@ -0,0 +1,143 @@
+use error::DeliveryError;
+use futures::{
+    executor::{self, Notify, NotifyHandle},
+    sync::oneshot,
+    Async, Future, Poll,
+};
+use std::{
+    sync::Arc,
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+pub type DeliverySender = oneshot::Sender<Result<(), DeliveryError>>;
+
+/// Resolves when the broker acknowledges the publish. PubAck for QoS1 and
+/// PubComp for QoS2. QoS0 publishes resolve as soon as the event loop accepts
+/// them, before they are written to the network. Resolving doesn't mean a QoS0
+/// publish reached the broker.
+///
+/// Resolves with an error if the publish is thrown away before it is
+/// acknowledged (clean session reconnection or event loop shutdown)
+#[must_use = "futures do nothing unless polled"]
+#[derive(Debug)]
+pub struct DeliveryToken {
+    rx: oneshot::Receiver<Result<(), DeliveryError>>,
+}
+
+pub fn channel() -> (DeliverySender, DeliveryToken) {
+    let (tx, rx) = oneshot::channel();
+    (tx, DeliveryToken { rx })
+}
+
+impl DeliveryToken {
+    /// Blocks the current thread till the publish is acknowledged or the timeout
+    /// elapses. Doesn't need a runtime. Don't call this from a task of the executor
+    /// running the event loop as the acknowledgement can't arrive while it's blocked
+    pub fn wait_timeout(self, timeout: Duration) -> Result<(), DeliveryError> {
+        let deadline = Instant::now() + timeout;
+        let notify = NotifyHandle::from(Arc::new(ThreadNotify(thread::current())));
+        let mut token = executor::spawn(self);
+
+        loop {
+            if let Async::Ready(()) = token.poll_future_notify(&notify, 0)? {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DeliveryError::Timeout);
+            }
+
+            // spurious wakeups just poll the token again
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+/// Wakes the thread blocked in `wait_timeout`
+struct ThreadNotify(Thread);
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _id: usize) {
+        self.0.unpark();
+    }
+}
+
+impl Future for DeliveryToken {
+    type Item = ();
+    type Error = DeliveryError;
+
+    fn poll(&mut self) -> Poll<(), DeliveryError> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Ok(()))) => Ok(Async::Ready(())),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(DeliveryError::Dropped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::channel;
+    use error::DeliveryError;
+    use futures::future;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+    use tokio::runtime::current_thread;
+
+    #[test]
+    fn wait_timeout_should_resolve_when_acknowledged() {
+        let (tx, token) = channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            tx.send(Ok(())).unwrap();
+        });
+
+        match token.wait_timeout(Duration::from_secs(10)) {
+            Ok(()) => (),
+            v => panic!("Should be acknowledged. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn wait_timeout_should_fail_when_not_acknowledged_in_time() {
+        let (_tx, token) = channel();
+        let start = Instant::now();
+
+        match token.wait_timeout(Duration::from_millis(200)) {
+            Err(DeliveryError::Timeout) => (),
+            v => panic!("Should time out. Got = {:?}", v),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn wait_timeout_should_fail_when_publish_is_dropped() {
+        let (tx, token) = channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(tx);
+        });
+
+        match token.wait_timeout(Duration::from_secs(10)) {
+            Err(DeliveryError::Dropped) => (),
+            v => panic!("Should be dropped. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn wait_timeout_should_work_inside_a_runtime() {
+        let (tx, token) = channel();
+        tx.send(Err(DeliveryError::SessionCleaned)).unwrap();
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        match rt.block_on(future::lazy(|| future::ok::<_, ()>(token.wait_timeout(Duration::from_secs(10))))) {
+            Ok(Err(DeliveryError::SessionCleaned)) => (),
+            v => panic!("Should fail with the error of the event loop. Got = {:?}", v),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use bytes::BytesMut;
+use client::{mqttstate::MqttState, session, Notification, Request};
+use codec::MqttCodec;
+use error::{ConnectError, EngineError, NetworkError};
+use mqtt311::Packet;
+use std::time::Instant;
+use tokio_codec::Decoder;
+use MqttOptions;
+
+/// Result of feeding an input to `MqttEngine`. Packets should be written to the
+/// network in order
+#[derive(Debug, Default)]
+pub struct EngineOutput {
+    pub packets: Vec<Packet>,
+    pub notifications: Vec<Notification>,
+    /// `MqttEngine::handle_timeout` should be called at this time. `None` while
+    /// there is no connection or keep alive is disabled
+    pub deadline: Option<Instant>,
+}
+
+/// Failure of `MqttEngine::handle_bytes`. Packets handled before the failing one have
+/// already changed the state, so their output should still be written and notified
+/// before the network connection is closed
+#[derive(Debug)]
+pub struct PartialOutput {
+    pub output: EngineOutput,
+    pub error: EngineError,
+}
+
+/// Mqtt protocol state machine without any io or clock. The caller owns the network
+/// connection and the timers and drives the engine with incoming bytes (or packets),
+/// user requests and timeouts, always passing the current time. Useful for custom
+/// event loops, embedded targets and deterministic tests
+#[derive(Debug)]
+pub struct MqttEngine {
+    state: MqttState,
+    codec: MqttCodec,
+    incoming: BytesMut,
+    ping_at: Option<Instant>,
+}
+
+impl MqttEngine {
+    pub fn new(opts: MqttOptions) -> MqttEngine {
+        let codec = MqttCodec::new(opts.max_incoming_packet_size());
+        MqttEngine { state: MqttState::new(opts),
+                     codec,
+                     incoming: BytesMut::new(),
+                     ping_at: None }
+    }
+
+    /// Loads unacknowledged messages of the previous run of the process from the session
+    /// store of the options. They are returned again after the connack
+    pub fn restore_session(&mut self) -> Result<(), EngineError> {
+        let session = session::load_session(&self.state.opts).map_err(ConnectError::Io)?;
+        self.state.restore_session(session);
+        Ok(())
+    }
+
+    /// Starts a new mqtt connection. Returns the connect packet to be written once the
+    /// network connection is established. Bytes buffered from the previous connection
+    /// are thrown away. Unacknowledged publishes of a persistent session are returned
+    /// again after the connack
+    pub fn connect(&mut self, now: Instant) -> Result<EngineOutput, EngineError> {
+        self.incoming.clear();
+        self.ping_at = None;
+
+        let connect = self.state.handle_outgoing_connect(now)?;
+        Ok(self.output(vec![Packet::Connect(connect)], Vec::new()))
+    }
+
+    /// Handles all the complete packets in the incoming bytes. Partial packets are
+    /// buffered till the rest of the bytes arrive. Stops at the first packet which fails
+    /// and returns the output of the packets handled before it along with the error
+    pub fn handle_bytes(&mut self, bytes: &[u8], now: Instant) -> Result<EngineOutput, PartialOutput> {
+        self.incoming.extend_from_slice(bytes);
+
+        let mut output = self.output(Vec::new(), Vec::new());
+        loop {
+            let packet = match self.codec.decode(&mut self.incoming) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(output),
+                Err(e) => return Err(PartialOutput { output, error: NetworkError::Io(e).into() }),
+            };
+
+            match self.handle_packet(packet, now) {
+                Ok(EngineOutput { packets, notifications, deadline }) => {
+                    output.packets.extend(packets);
+                    output.notifications.extend(notifications);
+                    output.deadline = deadline;
+                }
+                Err(error) => return Err(PartialOutput { output, error }),
+            }
+        }
+    }
+
+    /// Handles an incoming packet which is already decoded
+    pub fn handle_packet(&mut self, packet: Packet, now: Instant) -> Result<EngineOutput, EngineError> {
+        let (packets, notifications) = match packet {
+            Packet::Connack(connack) => {
+                let session_present = connack.session_present;
+                self.state.handle_incoming_connack(connack, now)?;
+
+                let notification = Notification::Connected(session_present, self.state.opts.broker_address());
+                let packets = self.state.handle_reconnection().into_iter().collect();
+                (packets, vec![notification])
+            }
+            packet => {
+                let (notification, request) = self.state.handle_incoming_mqtt_packet(packet, now)?;
+                let packets = match request {
+                    Request::None => Vec::new(),
+                    request => vec![request.into()],
+                };
+                let notifications = match notification {
+                    Notification::None => Vec::new(),
+                    notification => vec![notification],
+                };
+                (packets, notifications)
+            }
+        };
+
+        self.ping_at = self.keep_alive_deadline(now);
+        Ok(self.output(packets, notifications))
+    }
+
+    /// Handles a user request. `Request::Reconnect` updates the options and fails with
+    /// `NetworkError::UserReconnect`. The caller should close the network connection
+    /// and `connect` again
+    pub fn handle_request(&mut self, request: Request, now: Instant) -> Result<EngineOutput, EngineError> {
+        let packet = match request {
+            Request::Reconnect(opts) => {
+                self.state.opts = opts;
+                return Err(NetworkError::UserReconnect.into());
+            }
+            Request::TrackedPublish(publish, delivery_tx) => {
+                let publish = self.state.handle_outgoing_tracked_publish(publish, delivery_tx)?;
+                Packet::Publish(publish)
+            }
+            Request::None => return Ok(self.output(Vec::new(), Vec::new())),
+            request => self.state.handle_outgoing_mqtt_packet(request.into(), now)?,
+        };
+
+        Ok(self.output(vec![packet], Vec::new()))
+    }
+
+    /// Pings the broker when the keep alive deadline is reached. Fails when the response
+    /// to the previous ping isn't received or nothing is received for longer than the
+    /// keep alive. Should be called at `EngineOutput::deadline`
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<EngineOutput, EngineError> {
+        match self.ping_at {
+            Some(ping_at) if now >= ping_at => {
+                self.state.handle_outgoing_ping(now)?;
+                self.ping_at = self.keep_alive_deadline(now);
+                Ok(self.output(vec![Packet::Pingreq], Vec::new()))
+            }
+            _ => Ok(self.output(Vec::new(), Vec::new())),
+        }
+    }
+
+    /// `true` while `max_inflight` publishes are waiting for acks. Callers should hold
+    /// back new qos1 & qos2 publishes till an incoming ack makes room
+    pub fn is_inflight_full(&self) -> bool {
+        self.state.is_inflight_full()
+    }
+
+    /// Time at which the network connection should be closed and `connect` called
+    /// again to use a fresh token. `None` for credentials which don't expire
+    pub fn token_refresh_at(&self) -> Option<Instant> {
+        self.state.token_refresh_at()
+    }
+
+    fn keep_alive_deadline(&self, now: Instant) -> Option<Instant> {
+        let keep_alive = self.state.opts.keep_alive();
+        if keep_alive.as_secs() == 0 {
+            return None;
+        }
+
+        Some(now + keep_alive)
+    }
+
+    fn output(&self, packets: Vec<Packet>, notifications: Vec<Notification>) -> EngineOutput {
+        EngineOutput { packets,
+                       notifications,
+                       deadline: self.ping_at }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MqttEngine, PartialOutput};
+    use client::{Notification, Request};
+    use error::{EngineError, NetworkError};
+    use mqtt311::{Packet, PacketIdentifier, Publish, QoS};
+    use mqttoptions::MqttOptions;
+    use std::{sync::Arc, time::{Duration, Instant}};
+
+    fn build_engine() -> MqttEngine {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_keep_alive(10);
+        MqttEngine::new(opts)
+    }
+
+    fn publish(qos: QoS) -> Publish {
+        Publish { dup: false,
+                  qos,
+                  retain: false,
+                  pkid: None,
+                  topic_name: "hello/world".to_owned(),
+                  payload: Arc::new(vec![1, 2, 3]) }
+    }
+
+    #[test]
+    fn connect_and_connack_split_across_reads_should_notify_connection() {
+        let mut engine = build_engine();
+        let t0 = Instant::now();
+
+        let output = engine.connect(t0).unwrap();
+        match output.packets.as_slice() {
+            [Packet::Connect(connect)] => assert_eq!(connect.client_id, "test-id"),
+            p => panic!("Should send connect. Got = {:?}", p),
+        }
+        assert_eq!(output.deadline, None);
+
+        let output = engine.handle_bytes(&[0x20, 0x02], t0).unwrap();
+        assert!(output.notifications.is_empty());
+
+        let output = engine.handle_bytes(&[0x00, 0x00], t0).unwrap();
+        match output.notifications.as_slice() {
+            [Notification::Connected(false, _)] => (),
+            n => panic!("Should notify connection. Got = {:?}", n),
+        }
+        assert_eq!(output.deadline, Some(t0 + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn publish_ack_and_ping_should_be_driven_by_the_given_time() {
+        let mut engine = build_engine();
+        let t0 = Instant::now();
+        engine.connect(t0).unwrap();
+        engine.handle_bytes(&[0x20, 0x02, 0x00, 0x00], t0).unwrap();
+
+        let output = engine.handle_request(Request::Publish(publish(QoS::AtLeastOnce)), t0).unwrap();
+        match output.packets.as_slice() {
+            [Packet::Publish(publish)] => assert_eq!(publish.pkid, Some(PacketIdentifier(1))),
+            p => panic!("Should send publish. Got = {:?}", p),
+        }
+
+        let output = engine.handle_packet(Packet::Puback(PacketIdentifier(1)), t0).unwrap();
+        assert!(output.packets.is_empty());
+        match output.notifications.as_slice() {
+            [Notification::PubAck(PacketIdentifier(1))] => (),
+            n => panic!("Should notify puback. Got = {:?}", n),
+        }
+
+        // nothing to do before the deadline
+        let output = engine.handle_timeout(t0 + Duration::from_secs(5)).unwrap();
+        assert!(output.packets.is_empty());
+
+        let output = engine.handle_timeout(t0 + Duration::from_secs(10)).unwrap();
+        match output.packets.as_slice() {
+            [Packet::Pingreq] => (),
+            p => panic!("Should ping. Got = {:?}", p),
+        }
+        assert_eq!(output.deadline, Some(t0 + Duration::from_secs(20)));
+
+        // no ping response for the whole keep alive
+        match engine.handle_timeout(t0 + Duration::from_secs(20)) {
+            Err(EngineError::Network(NetworkError::Timeout)) => (),
+            v => panic!("Should throw timeout error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn unexpected_incoming_packet_should_throw_error() {
+        let mut engine = build_engine();
+        let t0 = Instant::now();
+        engine.connect(t0).unwrap();
+        engine.handle_bytes(&[0x20, 0x02, 0x00, 0x00], t0).unwrap();
+
+        match engine.handle_packet(Packet::Pingreq, t0) {
+            Err(EngineError::Network(NetworkError::UnexpectedPacket(Packet::Pingreq))) => (),
+            v => panic!("Should throw unexpected packet error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn failing_packet_should_not_drop_output_of_earlier_packets() {
+        let mut engine = build_engine();
+        let t0 = Instant::now();
+        engine.connect(t0).unwrap();
+        engine.handle_bytes(&[0x20, 0x02, 0x00, 0x00], t0).unwrap();
+        engine.handle_request(Request::Publish(publish(QoS::AtLeastOnce)), t0).unwrap();
+
+        // puback for the publish followed by a pingreq which brokers never send
+        match engine.handle_bytes(&[0x40, 0x02, 0x00, 0x01, 0xC0, 0x00], t0) {
+            Err(PartialOutput { output, error: EngineError::Network(NetworkError::UnexpectedPacket(Packet::Pingreq)) }) => {
+                match output.notifications.as_slice() {
+                    [Notification::PubAck(PacketIdentifier(1))] => (),
+                    n => panic!("Should notify puback. Got = {:?}", n),
+                }
+            }
+            v => panic!("Should throw unexpected packet error. Got = {:?}", v),
+        }
+    }
+}
@@ -1,15 +1,33 @@
+use codec::publish_packet_size;
 use crossbeam_channel;
-use error::{ClientError, ConnectError};
-use futures::{sync::mpsc, Future, Sink};
-use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, SubscribeTopic};
-use std::sync::Arc;
+use error::{ClientError, ConnectError, NetworkError};
+use futures::{sync::mpsc, task::AtomicTask, Future, Sink};
+use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, SubscribeReturnCodes, SubscribeTopic, Unsubscribe};
+use std::{sync::Arc, time::Duration};
 use MqttOptions;
 
+pub use client::asyncclient::{AsyncMqttClient, NotificationStream};
+pub use client::delivery::DeliveryToken;
+pub use client::engine::{EngineOutput, MqttEngine, PartialOutput};
+pub use client::publish::PublishBuilder;
+
+use client::delivery::DeliverySender;
+
+pub mod asyncclient;
+pub(crate) mod cloud;
 pub mod connection;
+pub mod credentials;
+pub mod delivery;
+pub mod engine;
 pub mod mqttasync;
 pub mod mqttstate;
 pub mod network;
+pub(crate) mod pinning;
 pub mod prepend;
+pub mod proxy;
+pub mod publish;
+pub mod session;
+pub mod websocket;
 
 #[derive(Debug)]
 pub enum Notification {
@@ -18,7 +36,24 @@ pub enum Notification {
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
-    SubAck(PacketIdentifier),
+    /// Subscription packet id and the broker's return code for each requested topic,
+    /// in the order the topics were subscribed
+    SubAck(PacketIdentifier, Vec<(String, SubscribeReturnCodes)>),
+    UnsubAck(PacketIdentifier),
+    /// Mqtt connection is established. Holds the session present flag of the connack
+    /// and the address of the broker
+    Connected(bool, (String, u16)),
+    /// Connection is lost because of this error
+    Disconnected(NetworkError),
+    /// Reconnection attempt number (since the last stable connection) and
+    /// the delay before the attempt
+    Reconnecting(u32, Duration),
+    /// Event loop gave up reconnecting after these many attempts
+    ReconnectAttemptsExhausted(u32),
+    /// Broker refused the connection with this connack return code
+    ConnectionRefused(u8),
+    /// Number of notifications thrown away because the notification channel was full
+    Dropped(u64),
     None,
 }
 
@@ -26,7 +61,10 @@ pub enum Notification {
 #[derive(Debug)]
 pub enum Request {
     Publish(Publish),
+    /// Publish which notifies the sender when it is acknowledged
+    TrackedPublish(Publish, DeliverySender),
     Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
@@ -47,6 +85,8 @@ pub struct UserHandle {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
     notification_rx: crossbeam_channel::Receiver<Notification>,
+    // wakes the notification stream of the async client
+    notification_task: Arc<AtomicTask>,
 }
 
 #[derive(Clone)]
@@ -58,51 +98,95 @@ pub struct MqttClient {
 
 impl MqttClient {
     pub fn start(opts: MqttOptions) -> Result<(Self, crossbeam_channel::Receiver<Notification>), ConnectError> {
+        let max_packet_size = opts.max_packet_size();
         let UserHandle {
             request_tx,
             command_tx,
-            notification_rx
+            notification_rx,
+            ..
         } = connection::Connection::run(opts)?;
 
-        //TODO: Remove max packet size hardcode
         let client = MqttClient { request_tx,
                                   command_tx,
-                                  max_packet_size: 1000 };
+                                  max_packet_size };
 
         Ok((client, notification_rx))
     }
 
+    /// Same as `start` but instead of spawning a thread, returns the event loop as a future
+    /// to be spawned on the caller's executor. See `Connection::eventloop`. Methods of the client
+    /// block till the request is queued. Don't call them from a task of the executor running the
+    /// event loop. Use `AsyncMqttClient` instead
+    pub fn eventloop(opts: MqttOptions)
+                     -> (Self,
+                         crossbeam_channel::Receiver<Notification>,
+                         impl Future<Item = (), Error = ConnectError> + Send) {
+        let max_packet_size = opts.max_packet_size();
+        let (user_handle, eventloop) = connection::Connection::eventloop(opts);
+        let UserHandle {
+            request_tx,
+            command_tx,
+            notification_rx,
+            ..
+        } = user_handle;
+
+        let client = MqttClient { request_tx,
+                                  command_tx,
+                                  max_packet_size };
+
+        (client, notification_rx, eventloop)
+    }
+
     pub fn publish<S, V>(&mut self, topic: S, qos: QoS, payload: V) -> Result<(), ClientError>
     where S: Into<String>, V: Into<Vec<u8>>
     {
-        let payload = payload.into();
-        if payload.len() > self.max_packet_size {
-            return Err(ClientError::PacketSizeLimitExceeded);
-        }
-
-        let publish = Publish { dup: false,
-                                qos,
-                                retain: false,
-                                topic_name: topic.into(),
-                                pkid: None,
-                                payload: Arc::new(payload) };
+        self.publish_with(PublishBuilder::new(topic, qos, payload))
+    }
 
+    /// Publishes with all the options of the builder (retain, dup). Invalid
+    /// topic names are rejected here instead of being sent to the broker
+    pub fn publish_with(&mut self, builder: PublishBuilder) -> Result<(), ClientError> {
+        let publish = publish_packet(builder, self.max_packet_size)?;
         let tx = &mut self.request_tx;
         tx.send(Request::Publish(publish)).wait()?;
         Ok(())
     }
 
+    /// Publishes and returns a token which resolves when the broker acknowledges
+    /// the publish
+    pub fn publish_with_token(&mut self, builder: PublishBuilder) -> Result<DeliveryToken, ClientError> {
+        let publish = publish_packet(builder, self.max_packet_size)?;
+        let (delivery_tx, token) = delivery::channel();
+
+        let tx = &mut self.request_tx;
+        tx.send(Request::TrackedPublish(publish, delivery_tx)).wait()?;
+        Ok(token)
+    }
+
     pub fn subscribe<S>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError>
     where S: Into<String>
     {
-        let topic = SubscribeTopic { topic_path: topic.into(), qos };
-        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics: vec![topic] };
+        self.subscribe_many(vec![(topic.into(), qos)])
+    }
 
+    /// Subscribes to all the topics with a single subscribe packet. Broker's
+    /// response for each topic is notified with `Notification::SubAck`
+    pub fn subscribe_many(&mut self, topics: Vec<(String, QoS)>) -> Result<(), ClientError> {
+        let subscribe = subscribe_packet(topics)?;
         let tx = &mut self.request_tx;
         tx.send(Request::Subscribe(subscribe)).wait()?;
         Ok(())
     }
 
+    pub fn unsubscribe<S>(&mut self, topic: S) -> Result<(), ClientError>
+    where S: Into<String>
+    {
+        let unsubscribe = unsubscribe_packet(topic.into());
+        let tx = &mut self.request_tx;
+        tx.send(Request::Unsubscribe(unsubscribe)).wait()?;
+        Ok(())
+    }
+
     pub fn pause(&mut self) -> Result<(), ClientError> {
         let tx  = &mut self.command_tx;
         tx.send(Command::Pause).wait()?;
@@ -121,3 +205,28 @@ impl MqttClient {
         Ok(())
     }
 }
+
+/// Validates the publish of the builder against the packet size limit
+fn publish_packet(builder: PublishBuilder, max_packet_size: usize) -> Result<Publish, ClientError> {
+    let publish = builder.build()?;
+    if publish_packet_size(&publish) > max_packet_size {
+        return Err(ClientError::PacketSizeLimitExceeded);
+    }
+
+    Ok(publish)
+}
+
+fn subscribe_packet(topics: Vec<(String, QoS)>) -> Result<Subscribe, ClientError> {
+    if topics.is_empty() {
+        return Err(ClientError::ZeroSubscriptions);
+    }
+
+    let topics = topics.into_iter()
+                       .map(|(topic_path, qos)| SubscribeTopic { topic_path, qos })
+                       .collect();
+    Ok(Subscribe { pkid: PacketIdentifier::zero(), topics })
+}
+
+fn unsubscribe_packet(topic: String) -> Unsubscribe {
+    Unsubscribe { pkid: PacketIdentifier::zero(), topics: vec![topic] }
+}
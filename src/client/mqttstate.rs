@@ -1,14 +1,25 @@
 use std::{
-    collections::VecDeque,
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
     result::Result,
     time::{Duration, Instant},
 };
 
-use client::{Notification, Request};
-use error::{ConnectError, NetworkError};
-use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Subscribe};
+use client::{
+    delivery::DeliverySender,
+    session::{Session, SessionUpdate},
+    Notification,
+    Request,
+};
+use codec::publish_packet_size;
+use error::{ConnectError, DeliveryError, NetworkError};
+use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Suback, Subscribe, Unsubscribe};
 use mqttoptions::MqttOptions;
 
+// Packet identifiers are non zero u16s
+const MAX_PKIDS: usize = 65_535;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum MqttConnectionStatus {
     Handshake,
@@ -25,10 +36,19 @@ pub(crate) struct MqttState {
     await_pingresp: bool,
     last_network_activity: Instant,
     last_pkid: PacketIdentifier,
-
-    // Stores outgoing data to handle quality of service
-    outgoing_pub: VecDeque<Publish>, // QoS1 & 2 publishes
-    outgoing_rel: VecDeque<PacketIdentifier>,
+    // Time to reconnect with a fresh token before the token of this connection expires
+    token_refresh_at: Option<Instant>,
+
+    // Stores outgoing data to handle quality of service. Publishes are looked up by
+    // pkid on acks and the sequence number keeps their order for retransmission
+    outgoing_pub: HashMap<u16, (u64, Publish)>, // QoS1 & 2 publishes
+    outgoing_seq: u64,
+    outgoing_rel: HashSet<u16>,
+    // Senders to notify users when their publishes are acknowledged (by pkid)
+    outgoing_acks: HashMap<u16, DeliverySender>,
+    // Subscribes and unsubscribes waiting for acks
+    outgoing_sub: VecDeque<Subscribe>,
+    outgoing_unsub: VecDeque<PacketIdentifier>,
 
     // Store incoming data to handle quality of service
     incoming_pub: VecDeque<PacketIdentifier>, // QoS2 publishes
@@ -48,25 +68,34 @@ impl MqttState {
                     await_pingresp: false,
                     last_network_activity: Instant::now(),
                     last_pkid: PacketIdentifier(0),
-                    outgoing_pub: VecDeque::new(),
-                    outgoing_rel: VecDeque::new(),
+                    token_refresh_at: None,
+                    outgoing_pub: HashMap::new(),
+                    outgoing_seq: 0,
+                    outgoing_rel: HashSet::new(),
+                    outgoing_acks: HashMap::new(),
+                    outgoing_sub: VecDeque::new(),
+                    outgoing_unsub: VecDeque::new(),
                     incoming_pub: VecDeque::new() }
     }
 
-    pub fn handle_outgoing_mqtt_packet(&mut self, packet: Packet) -> Result<Packet, NetworkError> {
+    pub fn handle_outgoing_mqtt_packet(&mut self, packet: Packet, now: Instant) -> Result<Packet, NetworkError> {
         match packet {
             Packet::Publish(publish) => {
                 let publish = self.handle_outgoing_publish(publish)?;
                 Ok(Packet::Publish(publish))
             }
             Packet::Pingreq => {
-                let _ping = self.handle_outgoing_ping()?;
+                let _ping = self.handle_outgoing_ping(now)?;
                 Ok(Packet::Pingreq)
             }
             Packet::Subscribe(subs) => {
                 let subscription = self.handle_outgoing_subscribe(subs)?;
                 Ok(Packet::Subscribe(subscription))
             }
+            Packet::Unsubscribe(unsubscribe) => {
+                let unsubscribe = self.handle_outgoing_unsubscribe(unsubscribe)?;
+                Ok(Packet::Unsubscribe(unsubscribe))
+            }
             _ => Ok(packet),
         }
     }
@@ -78,34 +107,51 @@ impl MqttState {
     //
     // E.g For incoming QoS1 publish packet, this method returns (Publish, Puback). Publish packet will
     // be forwarded to user and Pubck packet will be written to network
-    pub fn handle_incoming_mqtt_packet(&mut self, packet: Packet) -> Result<(Notification, Request), NetworkError> {
-        self.update_last_in_control_time();
+    pub fn handle_incoming_mqtt_packet(&mut self, packet: Packet, now: Instant) -> Result<(Notification, Request), NetworkError> {
+        self.update_last_in_control_time(now);
 
         match packet {
             Packet::Pingresp => self.handle_incoming_pingresp(),
             Packet::Publish(publish) => self.handle_incoming_publish(publish.clone()),
-            Packet::Suback(_pkid) => Ok((Notification::None, Request::None)),
+            Packet::Suback(suback) => self.handle_incoming_suback(suback),
             Packet::Puback(pkid) => self.handle_incoming_puback(pkid),
             Packet::Pubrec(pkid) => self.handle_incoming_pubrec(pkid),
             Packet::Pubrel(pkid) => self.handle_incoming_pubrel(pkid),
             Packet::Pubcomp(pkid) => self.handle_incoming_pubcomp(pkid),
-            _ => panic!("{:?}", packet),
+            Packet::Unsuback(pkid) => self.handle_incoming_unsuback(pkid),
+            _ => {
+                error!("Unexpected packet: {:?}", packet);
+                Err(NetworkError::UnexpectedPacket(packet))
+            }
         }
     }
 
-    pub fn handle_outgoing_connect(&mut self) -> Result<Connect, ConnectError> {
+    pub fn handle_outgoing_connect(&mut self, now: Instant) -> Result<Connect, ConnectError> {
         self.connection_status = MqttConnectionStatus::Handshake;
-        self.opts.connect_packet()
+        let connect = self.opts.connect_packet()?;
+
+        // refresh a bit before the expiry to account for clock skew and the time
+        // it takes to reconnect
+        self.token_refresh_at = self.opts.token_lifetime().and_then(|lifetime| {
+                                                                   let margin = cmp::min(lifetime / 10, Duration::from_secs(60));
+                                                                   now.checked_add(lifetime - margin)
+                                                               });
+
+        Ok(connect)
     }
 
-    pub fn handle_incoming_connack(&mut self, connack: Connack) -> Result<(), ConnectError> {
+    pub fn token_refresh_at(&self) -> Option<Instant> {
+        self.token_refresh_at
+    }
+
+    pub fn handle_incoming_connack(&mut self, connack: Connack, now: Instant) -> Result<(), ConnectError> {
         let response = connack.code;
         if response != ConnectReturnCode::Accepted {
             self.connection_status = MqttConnectionStatus::Disconnected;
             Err(ConnectError::MqttConnectionRefused(response.to_u8()))
         } else {
             self.connection_status = MqttConnectionStatus::Connected;
-            self.handle_previous_session();
+            self.handle_previous_session(now)?;
 
             Ok(())
         }
@@ -116,69 +162,135 @@ impl MqttState {
             VecDeque::new()
         } else {
             //TODO: Write unittest for checking state during reconnection
-            self.outgoing_pub
-                .clone()
-                .into_iter()
-                .map(Packet::Publish)
+            let mut publishes: Vec<&(u64, Publish)> = self.outgoing_pub.values().collect();
+            publishes.sort_by_key(|(seq, _)| *seq);
+
+            // broker waits for the release of qos2 publishes it has already received
+            let rels = self.outgoing_rel.iter().map(|pkid| Packet::Pubrel(PacketIdentifier(*pkid)));
+            rels.chain(publishes.into_iter().map(|(_, publish)| Packet::Publish(publish.clone())))
                 .collect()
         }
     }
 
-    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Publish {
+    /// Restores the session of the previous run of the process. Unacknowledged publishes
+    /// are retransmitted after the connection
+    pub fn restore_session(&mut self, session: Session) {
+        for publish in session.outgoing_pub {
+            if let Some(pkid) = publish.pkid {
+                self.last_pkid = pkid;
+                self.outgoing_seq += 1;
+                self.outgoing_pub.insert(pkid.0, (self.outgoing_seq, publish));
+            }
+        }
+
+        self.outgoing_rel.extend(session.outgoing_rel.into_iter().map(|pkid| pkid.0));
+        self.incoming_pub.extend(session.incoming_pub);
+    }
+
+    // changes are persisted before they are acted upon so that they survive restarts
+    fn persist(&self, update: SessionUpdate) -> io::Result<()> {
+        match self.opts.session_store() {
+            Some(ref store) if !self.opts.clean_session() => store.update(&update),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of qos1 & qos2 publishes waiting for acknowledgment
+    pub fn inflight(&self) -> usize {
+        self.outgoing_pub.len() + self.outgoing_rel.len()
+    }
+
+    /// New requests shouldn't be taken while this is `true`. Also `true` when
+    /// all the packet identifiers are in use
+    pub fn is_inflight_full(&self) -> bool {
+        self.inflight() >= self.opts.max_inflight() || self.pkids_in_use() >= MAX_PKIDS
+    }
+
+    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Result<Publish, NetworkError> {
         let publish = if publish.pkid == None {
-            let pkid = self.next_pkid();
+            let pkid = self.next_pkid()?;
             publish.pkid = Some(pkid);
             publish
         } else {
             publish
         };
 
-        self.outgoing_pub.push_back(publish.clone());
-        publish
+        if let Some(PacketIdentifier(pkid)) = publish.pkid {
+            self.persist(SessionUpdate::Publish(publish.clone()))?;
+            self.outgoing_seq += 1;
+            self.outgoing_pub.insert(pkid, (self.outgoing_seq, publish.clone()));
+        }
+
+        Ok(publish)
     }
 
     /// Sets next packet id if pkid is None (fresh publish) and adds it to the
     /// outgoing publish queue
     pub fn handle_outgoing_publish(&mut self, publish: Publish) -> Result<Publish, NetworkError> {
-        if publish.payload.len() > self.opts.max_packet_size() {
+        if publish_packet_size(&publish) > self.opts.max_packet_size() {
             return Err(NetworkError::PacketSizeLimitExceeded);
         }
 
         let publish = match publish.qos {
             QoS::AtMostOnce => publish,
-            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish),
+            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish)?,
         };
 
         Ok(publish)
     }
 
-    pub fn handle_incoming_puback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
-        match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
-            Some(index) => {
-                let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
-                Ok((Notification::None, Request::None))
+    /// Same as `handle_outgoing_publish` but remembers `delivery_tx` to notify the
+    /// user when the publish is acknowledged
+    pub fn handle_outgoing_tracked_publish(&mut self,
+                                           publish: Publish,
+                                           delivery_tx: DeliverySender)
+                                           -> Result<Publish, NetworkError> {
+        let publish = self.handle_outgoing_publish(publish)?;
+
+        match publish.pkid {
+            Some(PacketIdentifier(pkid)) => {
+                self.outgoing_acks.insert(pkid, delivery_tx);
             }
+            // qos0 publishes don't have acks
             None => {
-                error!("Unsolicited puback packet: {:?}", pkid);
-                Err(NetworkError::Unsolicited)
+                let _ = delivery_tx.send(Ok(()));
             }
         }
+
+        Ok(publish)
     }
 
-    pub fn handle_incoming_pubrec(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
-        match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
-            Some(index) => {
-                let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
-                self.outgoing_rel.push_back(pkid);
+    fn complete_delivery(&mut self, pkid: PacketIdentifier, result: Result<(), DeliveryError>) {
+        if let Some(delivery_tx) = self.outgoing_acks.remove(&pkid.0) {
+            // user might not be interested in the result anymore
+            let _ = delivery_tx.send(result);
+        }
+    }
 
-                let notification = Notification::None;
-                let reply = Request::PubRel(pkid);
-                Ok((notification, reply))
-            }
-            None => {
-                error!("Unsolicited pubrec packet: {:?}", pkid);
-                Err(NetworkError::Unsolicited)
-            }
+    pub fn handle_incoming_puback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        if self.outgoing_pub.contains_key(&pkid.0) {
+            self.persist(SessionUpdate::PubAck(pkid))?;
+            self.outgoing_pub.remove(&pkid.0);
+            self.complete_delivery(pkid, Ok(()));
+            Ok((Notification::None, Request::None))
+        } else {
+            error!("Unsolicited puback packet: {:?}", pkid);
+            Err(NetworkError::Unsolicited)
+        }
+    }
+
+    pub fn handle_incoming_pubrec(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        if self.outgoing_pub.contains_key(&pkid.0) {
+            self.persist(SessionUpdate::PubRec(pkid))?;
+            self.outgoing_pub.remove(&pkid.0);
+            self.outgoing_rel.insert(pkid.0);
+
+            let notification = Notification::None;
+            let reply = Request::PubRel(pkid);
+            Ok((notification, reply))
+        } else {
+            error!("Unsolicited pubrec packet: {:?}", pkid);
+            Err(NetworkError::Unsolicited)
         }
     }
 
@@ -200,6 +312,7 @@ impl MqttState {
                 let request = Request::PubRec(pkid);
                 let notification = Notification::Publish(publish);
 
+                self.persist(SessionUpdate::IncomingPublish(pkid))?;
                 self.incoming_pub.push_back(pkid);
                 Ok((notification, request))
             }
@@ -209,6 +322,7 @@ impl MqttState {
     pub fn handle_incoming_pubrel(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
         match self.incoming_pub.iter().position(|x| *x == pkid) {
             Some(index) => {
+                self.persist(SessionUpdate::PubRel(pkid))?;
                 let _pkid = self.incoming_pub.remove(index);
                 let notification = Notification::None;
                 let reply = Request::PubComp(pkid);
@@ -222,28 +336,27 @@ impl MqttState {
     }
 
     pub fn handle_incoming_pubcomp(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
-        match self.outgoing_rel.iter().position(|x| *x == pkid) {
-            Some(index) => {
-                self.outgoing_rel.remove(index).expect("Wrong index");
-                Ok((Notification::None, Request::None))
-            }
-            _ => {
-                error!("Unsolicited pubcomp packet: {:?}", pkid);
-                Err(NetworkError::Unsolicited)
-            }
+        if self.outgoing_rel.contains(&pkid.0) {
+            self.persist(SessionUpdate::PubComp(pkid))?;
+            self.outgoing_rel.remove(&pkid.0);
+            self.complete_delivery(pkid, Ok(()));
+            Ok((Notification::None, Request::None))
+        } else {
+            error!("Unsolicited pubcomp packet: {:?}", pkid);
+            Err(NetworkError::Unsolicited)
         }
     }
 
     // reset the last control packet sent time
-    pub fn update_last_in_control_time(&mut self) {
-        self.last_network_activity = Instant::now();
+    pub fn update_last_in_control_time(&mut self, now: Instant) {
+        self.last_network_activity = now;
     }
 
     // check when the last control packet/pingreq packet
     // is received and return the status which tells if
     // keep alive time has exceeded
     // NOTE: status will be checked for zero keepalive times also
-    pub fn handle_outgoing_ping(&mut self) -> Result<(), NetworkError> {
+    pub fn handle_outgoing_ping(&mut self, now: Instant) -> Result<(), NetworkError> {
         // @ Prevents half open connections. Tcp writes will buffer up
         // with out throwing any error (till a timeout) when internet
         // is down. Even though broker closes the socket after timeout,
@@ -257,7 +370,11 @@ impl MqttState {
         let deviation = 100;
         let keep_alive = Duration::from_millis(keep_alive + deviation);
 
-        let elapsed = self.last_network_activity.elapsed();
+        let elapsed = if now > self.last_network_activity {
+            now - self.last_network_activity
+        } else {
+            Duration::from_secs(0)
+        };
         if elapsed >= keep_alive {
             error!("Elapsed time {:?} is greater than keep alive {:?}. Timeout error",
                    elapsed.as_secs(),
@@ -286,10 +403,11 @@ impl MqttState {
     }
 
     pub fn handle_outgoing_subscribe(&mut self, mut subscription: Subscribe) -> Result<Subscribe, NetworkError> {
-        let pkid = self.next_pkid();
+        let pkid = self.next_pkid()?;
 
         if self.connection_status == MqttConnectionStatus::Connected {
             subscription.pkid = pkid;
+            self.outgoing_sub.push_back(subscription.clone());
 
             Ok(subscription)
         } else {
@@ -298,44 +416,146 @@ impl MqttState {
         }
     }
 
-    // pub fn handle_incoming_suback(&mut self, ack: Suback) -> Result<(), SubackError> {
-    //     if ack.return_codes.iter().any(|v| *v == SubscribeReturnCodes::Failure) {
-    //         Err(SubackError::Rejected)
-    //     } else {
-    //         Ok(())
-    //     }
-    // }
+    pub fn handle_outgoing_unsubscribe(&mut self, mut unsubscribe: Unsubscribe) -> Result<Unsubscribe, NetworkError> {
+        let pkid = self.next_pkid()?;
 
-    fn handle_previous_session(&mut self) {
+        if self.connection_status == MqttConnectionStatus::Connected {
+            unsubscribe.pkid = pkid;
+            self.outgoing_unsub.push_back(pkid);
+
+            Ok(unsubscribe)
+        } else {
+            error!("State = {:?}. Shouldn't unsubscribe in this state", self.connection_status);
+            Err(NetworkError::InvalidState)
+        }
+    }
+
+    pub fn handle_incoming_unsuback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        match self.outgoing_unsub.iter().position(|x| *x == pkid) {
+            Some(index) => {
+                self.outgoing_unsub.remove(index).expect("Wrong index");
+                Ok((Notification::UnsubAck(pkid), Request::None))
+            }
+            None => {
+                error!("Unsolicited unsuback packet: {:?}", pkid);
+                Err(NetworkError::Unsolicited)
+            }
+        }
+    }
+
+    // Pairs each return code in the suback with the topic it was requested for.
+    // Rejected topics are reported with `SubscribeReturnCodes::Failure`. A suback
+    // without exactly one return code per topic is a protocol violation
+    pub fn handle_incoming_suback(&mut self, suback: Suback) -> Result<(Notification, Request), NetworkError> {
+        let pkid = suback.pkid;
+        match self.outgoing_sub.iter().position(|x| x.pkid == pkid) {
+            Some(index) => {
+                let subscription = self.outgoing_sub.remove(index).expect("Wrong index");
+                if subscription.topics.len() != suback.return_codes.len() {
+                    error!("Suback return code count {} doesn't match subscribed topic count {}",
+                           suback.return_codes.len(),
+                           subscription.topics.len());
+                    return Err(NetworkError::SubackMismatch(suback.return_codes.len(), subscription.topics.len()));
+                }
+
+                let topics = subscription.topics
+                                         .into_iter()
+                                         .map(|topic| topic.topic_path)
+                                         .zip(suback.return_codes.into_iter())
+                                         .collect();
+
+                Ok((Notification::SubAck(pkid, topics), Request::None))
+            }
+            None => {
+                error!("Unsolicited suback packet: {:?}", pkid);
+                Err(NetworkError::Unsolicited)
+            }
+        }
+    }
+
+    fn handle_previous_session(&mut self, now: Instant) -> io::Result<()> {
         self.await_pingresp = false;
 
+        // (un)subscribes aren't retransmitted. acks for them won't arrive on the new connection
+        self.outgoing_sub.clear();
+        self.outgoing_unsub.clear();
+
         if self.opts.clean_session() {
             self.outgoing_pub.clear();
+            self.outgoing_rel.clear();
+            self.incoming_pub.clear();
+
+            for (_, delivery_tx) in self.outgoing_acks.drain() {
+                let _ = delivery_tx.send(Err(DeliveryError::SessionCleaned));
+            }
+
+            if let Some(store) = self.opts.session_store() {
+                store.clear()?;
+            }
         }
 
-        self.last_network_activity = Instant::now();
+        self.last_network_activity = now;
+        Ok(())
     }
 
     // http://stackoverflow.com/questions/11115364/mqtt-messageid-practical-implementation
-    fn next_pkid(&mut self) -> PacketIdentifier {
+    // Ids of publishes, subscribes and unsubscribes waiting for acks are skipped so that
+    // an id isn't handed out twice after a wrap
+    fn next_pkid(&mut self) -> Result<PacketIdentifier, NetworkError> {
+        if self.pkids_in_use() >= MAX_PKIDS {
+            error!("All packet identifiers are in use");
+            return Err(NetworkError::PacketIdsExhausted);
+        }
+
         let PacketIdentifier(mut pkid) = self.last_pkid;
-        if pkid == 65_535 {
-            pkid = 0;
+        loop {
+            pkid = if pkid == 65_535 { 1 } else { pkid + 1 };
+            if !self.is_pkid_in_use(pkid) {
+                break;
+            }
         }
-        self.last_pkid = PacketIdentifier(pkid + 1);
-        self.last_pkid
+
+        self.last_pkid = PacketIdentifier(pkid);
+        Ok(self.last_pkid)
+    }
+
+    fn pkids_in_use(&self) -> usize {
+        self.outgoing_pub.len() + self.outgoing_rel.len() + self.outgoing_sub.len() + self.outgoing_unsub.len()
+    }
+
+    fn is_pkid_in_use(&self, pkid: u16) -> bool {
+        self.outgoing_pub.contains_key(&pkid)
+        || self.outgoing_rel.contains(&pkid)
+        || self.outgoing_sub.iter().any(|subscribe| subscribe.pkid.0 == pkid)
+        || self.outgoing_unsub.iter().any(|unsubscribe| unsubscribe.0 == pkid)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{sync::Arc, thread, time::Duration};
+    use std::{
+        collections::{HashSet, VecDeque},
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
 
     use super::{MqttConnectionStatus, MqttState};
-    use client::{Notification, Request};
-    use error::NetworkError;
+    use client::{
+        delivery,
+        session::{MemorySessionStore, Session, SessionStore, SessionUpdate},
+        Notification,
+        Request,
+    };
+    use error::{DeliveryError, NetworkError};
+    use futures::Future;
     use mqtt311::*;
     use mqttoptions::MqttOptions;
+    use proptest::prelude::*;
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
         Publish { dup: false,
@@ -366,11 +586,153 @@ mod test {
         let mut pkt_id = PacketIdentifier(0);
 
         for _ in 0..65536 {
-            pkt_id = mqtt.next_pkid();
+            pkt_id = mqtt.next_pkid().unwrap();
         }
         assert_eq!(PacketIdentifier(1), pkt_id);
     }
 
+    #[test]
+    fn pkids_in_use_should_be_skipped_across_millions_of_publishes() {
+        let mut mqtt = build_mqttstate();
+        let publish = |mqtt: &mut MqttState| {
+            let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+            publish.pkid.unwrap().0
+        };
+
+        // never acked. skipped on every wrap
+        let stuck: Vec<u16> = (0..10).map(|_| publish(&mut mqtt)).collect();
+        let mut window = VecDeque::new();
+        let mut in_use: HashSet<u16> = stuck.iter().cloned().collect();
+
+        for _ in 0..2_000_000 {
+            let pkid = publish(&mut mqtt);
+            assert!(in_use.insert(pkid), "Packet id {} handed out while in use", pkid);
+            window.push_back(pkid);
+
+            if window.len() > 100 {
+                let pkid = window.pop_front().unwrap();
+                in_use.remove(&pkid);
+                mqtt.handle_incoming_puback(PacketIdentifier(pkid)).unwrap();
+            }
+        }
+
+        for pkid in stuck {
+            mqtt.handle_incoming_puback(PacketIdentifier(pkid)).unwrap();
+        }
+    }
+
+    #[test]
+    fn exhausted_pkids_should_apply_backpressure() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_max_inflight(65_535);
+
+        for _ in 0..65_535 {
+            mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        }
+        assert!(mqtt.is_inflight_full());
+
+        match mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)) {
+            Err(NetworkError::PacketIdsExhausted) => (),
+            v => panic!("Should throw packet ids exhausted error. Got = {:?}", v),
+        }
+
+        mqtt.handle_incoming_puback(PacketIdentifier(300)).unwrap();
+        assert!(!mqtt.is_inflight_full());
+
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(300)));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Publish(QoS),
+        Subscribe,
+        // acknowledges the in flight packet at this index (modulo in flight count)
+        Ack(usize),
+    }
+
+    #[derive(Debug)]
+    enum Pending {
+        Puback,
+        Pubrec,
+        Pubcomp,
+        Suback,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![Just(Op::Publish(QoS::AtLeastOnce)),
+                    Just(Op::Publish(QoS::ExactlyOnce)),
+                    Just(Op::Subscribe),
+                    any::<usize>().prop_map(Op::Ack)]
+    }
+
+    // applies the operation and returns the allocated packet id with the ack it waits for
+    fn apply(mqtt: &mut MqttState, inflight: &mut Vec<(u16, Pending)>, op: Op) -> Option<(u16, Pending)> {
+        match op {
+            Op::Publish(qos) => {
+                let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(qos)).unwrap();
+                let pending = match qos {
+                    QoS::AtLeastOnce => Pending::Puback,
+                    _ => Pending::Pubrec,
+                };
+                Some((publish.pkid.unwrap().0, pending))
+            }
+            Op::Subscribe => {
+                let topic = SubscribeTopic { topic_path: "hello/world".to_owned(),
+                                             qos: QoS::AtMostOnce };
+                let subscribe = Subscribe { pkid: PacketIdentifier(0),
+                                            topics: vec![topic] };
+                let subscribe = mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+                Some((subscribe.pkid.0, Pending::Suback))
+            }
+            Op::Ack(_) if inflight.is_empty() => None,
+            Op::Ack(index) => {
+                let index = index % inflight.len();
+                let (pkid, pending) = inflight.swap_remove(index);
+                match pending {
+                    Pending::Puback => {
+                        mqtt.handle_incoming_puback(PacketIdentifier(pkid)).unwrap();
+                    }
+                    Pending::Pubrec => {
+                        mqtt.handle_incoming_pubrec(PacketIdentifier(pkid)).unwrap();
+                        inflight.push((pkid, Pending::Pubcomp));
+                    }
+                    Pending::Pubcomp => {
+                        mqtt.handle_incoming_pubcomp(PacketIdentifier(pkid)).unwrap();
+                    }
+                    Pending::Suback => {
+                        let suback = Suback { pkid: PacketIdentifier(pkid),
+                                              return_codes: vec![SubscribeReturnCodes::Success(QoS::AtMostOnce)] };
+                        mqtt.handle_incoming_suback(suback).unwrap();
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn allocated_pkids_should_never_be_in_use(last_pkid in 64_000u16..65_535,
+                                                  ops in prop::collection::vec(op(), 1..2_000)) {
+            let mut mqtt = build_mqttstate();
+            mqtt.connection_status = MqttConnectionStatus::Connected;
+            mqtt.last_pkid = PacketIdentifier(last_pkid);
+            let mut inflight = Vec::new();
+
+            for op in ops {
+                if let Some((pkid, pending)) = apply(&mut mqtt, &mut inflight, op) {
+                    prop_assert!(pkid != 0);
+                    prop_assert!(inflight.iter().all(|(id, _)| *id != pkid), "Packet id {} handed out while in use", pkid);
+                    inflight.push((pkid, pending));
+                }
+                prop_assert_eq!(mqtt.pkids_in_use(), inflight.len());
+            }
+        }
+    }
+
     #[test]
     fn outgoing_publish_handle_should_set_pkid_correctly_and_add_publish_to_queue_correctly() {
         let mut mqtt = build_mqttstate();
@@ -428,6 +790,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn outgoing_publish_handle_should_consider_topic_and_header_in_packetsize() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_max_packet_size(1);
+        let mut mqtt = MqttState::new(opts);
+
+        // payload fits in the limit but the whole packet doesn't
+        let publish = Publish { dup: false,
+                                qos: QoS::AtLeastOnce,
+                                retain: false,
+                                pkid: None,
+                                topic_name: "hello/world".to_owned(),
+                                payload: Arc::new(vec![0; 1020]) };
+
+        match mqtt.handle_outgoing_publish(publish) {
+            Err(NetworkError::PacketSizeLimitExceeded) => (),
+            _ => panic!("Should throw packet size limit error"),
+        }
+    }
+
     #[test]
     fn incoming_publish_should_be_added_to_queue_correctly() {
         let mut mqtt = build_mqttstate();
@@ -479,7 +860,7 @@ mod test {
         mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
         assert_eq!(mqtt.outgoing_pub.len(), 1);
 
-        let backup = mqtt.outgoing_pub.get(0).unwrap().clone();
+        let (_, backup) = mqtt.outgoing_pub.get(&2).unwrap().clone();
         assert_eq!(backup.pkid, Some(PacketIdentifier(2)));
 
         mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
@@ -500,14 +881,13 @@ mod test {
         assert_eq!(mqtt.outgoing_pub.len(), 1);
 
         // check if the remaining element's pkid is 1
-        let backup = mqtt.outgoing_pub.get(0).unwrap().clone();
+        let (_, backup) = mqtt.outgoing_pub.get(&1).unwrap().clone();
         assert_eq!(backup.pkid, Some(PacketIdentifier(1)));
 
         assert_eq!(mqtt.outgoing_rel.len(), 1);
 
         // check if the  element's pkid is 2
-        let pkid = *mqtt.outgoing_rel.get(0).unwrap();
-        assert_eq!(pkid, PacketIdentifier(2));
+        assert!(mqtt.outgoing_rel.contains(&2));
     }
 
     #[test]
@@ -563,6 +943,145 @@ mod test {
         assert_eq!(mqtt.outgoing_pub.len(), 0);
     }
 
+    #[test]
+    fn tracked_publish_should_complete_after_puback() {
+        let mut mqtt = build_mqttstate();
+
+        let (delivery_tx, token) = delivery::channel();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_tracked_publish(publish, delivery_tx).unwrap();
+        assert_eq!(mqtt.outgoing_acks.len(), 1);
+
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        assert_eq!(mqtt.outgoing_acks.len(), 0);
+        token.wait().unwrap();
+    }
+
+    #[test]
+    fn tracked_publish_should_complete_after_pubcomp() {
+        let mut mqtt = build_mqttstate();
+
+        let (delivery_tx, token) = delivery::channel();
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        mqtt.handle_outgoing_tracked_publish(publish, delivery_tx).unwrap();
+
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        assert_eq!(mqtt.outgoing_acks.len(), 1);
+
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(1)).unwrap();
+        token.wait().unwrap();
+    }
+
+    #[test]
+    fn tracked_publish_should_fail_when_session_is_cleaned_or_state_is_dropped() {
+        let mut mqtt = build_mqttstate();
+
+        let (delivery_tx, token) = delivery::channel();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_tracked_publish(publish, delivery_tx).unwrap();
+
+        mqtt.handle_previous_session(Instant::now()).unwrap();
+        match token.wait() {
+            Err(DeliveryError::SessionCleaned) => (),
+            v => panic!("Should throw session cleaned error. Got = {:?}", v),
+        }
+
+        let (delivery_tx, token) = delivery::channel();
+        let publish = build_outgoing_publish(QoS::AtLeastOnce);
+        mqtt.handle_outgoing_tracked_publish(publish, delivery_tx).unwrap();
+
+        drop(mqtt);
+        match token.wait() {
+            Err(DeliveryError::Dropped) => (),
+            v => panic!("Should throw dropped error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn incoming_suback_should_notify_return_code_of_every_topic() {
+        let mut mqtt = build_mqttstate();
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+
+        let topics = vec![SubscribeTopic { topic_path: "hello/world".to_owned(), qos: QoS::AtLeastOnce },
+                          SubscribeTopic { topic_path: "hello/rejected".to_owned(), qos: QoS::ExactlyOnce }];
+        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics };
+        let subscribe = mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+        assert_eq!(subscribe.pkid, PacketIdentifier(1));
+
+        let suback = Suback { pkid: PacketIdentifier(1),
+                              return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                                                 SubscribeReturnCodes::Failure] };
+
+        let (notification, _request) = mqtt.handle_incoming_mqtt_packet(Packet::Suback(suback), Instant::now()).unwrap();
+        match notification {
+            Notification::SubAck(pkid, topics) => {
+                assert_eq!(pkid, PacketIdentifier(1));
+                assert_eq!(topics,
+                           vec![("hello/world".to_owned(), SubscribeReturnCodes::Success(QoS::AtLeastOnce)),
+                                ("hello/rejected".to_owned(), SubscribeReturnCodes::Failure)]);
+            }
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        assert_eq!(mqtt.outgoing_sub.len(), 0);
+    }
+
+    #[test]
+    fn suback_with_missing_return_codes_should_throw_error() {
+        let mut mqtt = build_mqttstate();
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+
+        let topics = vec![SubscribeTopic { topic_path: "hello/world".to_owned(), qos: QoS::AtLeastOnce },
+                          SubscribeTopic { topic_path: "hello/dropped".to_owned(), qos: QoS::AtLeastOnce }];
+        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics };
+        mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+
+        let suback = Suback { pkid: PacketIdentifier(1),
+                              return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)] };
+        match mqtt.handle_incoming_mqtt_packet(Packet::Suback(suback), Instant::now()) {
+            Err(NetworkError::SubackMismatch(1, 2)) => (),
+            v => panic!("Should throw suback mismatch error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn outgoing_unsubscribe_should_set_pkid_and_wait_for_unsuback() {
+        let mut mqtt = build_mqttstate();
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+
+        let unsubscribe = Unsubscribe { pkid: PacketIdentifier::zero(),
+                                        topics: vec!["hello/world".to_owned()] };
+
+        let unsubscribe = mqtt.handle_outgoing_unsubscribe(unsubscribe).unwrap();
+        assert_eq!(unsubscribe.pkid, PacketIdentifier(1));
+        assert_eq!(mqtt.outgoing_unsub.len(), 1);
+
+        let unsuback = Packet::Unsuback(PacketIdentifier(1));
+        let (notification, request) = mqtt.handle_incoming_mqtt_packet(unsuback, Instant::now()).unwrap();
+
+        match notification {
+            Notification::UnsubAck(pkid) => assert_eq!(pkid, PacketIdentifier(1)),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        match request {
+            Request::None => (),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+
+        assert_eq!(mqtt.outgoing_unsub.len(), 0);
+    }
+
+    #[test]
+    fn unsolicited_unsuback_should_throw_error() {
+        let mut mqtt = build_mqttstate();
+
+        match mqtt.handle_incoming_unsuback(PacketIdentifier(1)) {
+            Err(NetworkError::Unsolicited) => (),
+            _ => panic!("Should throw unsolicited error"),
+        }
+    }
+
     #[test]
     fn outgoing_ping_handle_should_throw_errors_for_no_pingresp() {
         let mut mqtt = build_mqttstate();
@@ -572,15 +1091,15 @@ mod test {
         thread::sleep(Duration::from_secs(10));
 
         // should ping
-        assert_eq!((), mqtt.handle_outgoing_ping().unwrap());
+        assert_eq!((), mqtt.handle_outgoing_ping(Instant::now()).unwrap());
         // network activity other than pingresp
         let publish = build_outgoing_publish(QoS::AtLeastOnce);
-        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish)).unwrap();
-        mqtt.handle_incoming_mqtt_packet(Packet::Puback(PacketIdentifier(1))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish), Instant::now()).unwrap();
+        mqtt.handle_incoming_mqtt_packet(Packet::Puback(PacketIdentifier(1)), Instant::now()).unwrap();
         thread::sleep(Duration::from_secs(10));
 
         // should throw error because we didn't get pingresp for previous ping
-        match mqtt.handle_outgoing_ping() {
+        match mqtt.handle_outgoing_ping(Instant::now()) {
             Ok(_) => panic!("Should throw pingresp await error"),
             Err(NetworkError::AwaitPingResp) => (),
             Err(e) => panic!("Should throw pingresp await error. Error = {:?}", e),
@@ -597,7 +1116,7 @@ mod test {
         mqtt.connection_status = MqttConnectionStatus::Connected;
         thread::sleep(Duration::from_secs(12));
 
-        match mqtt.handle_outgoing_ping() {
+        match mqtt.handle_outgoing_ping(Instant::now()) {
             Err(NetworkError::Timeout) => (),
             _ => panic!("Should throw timeout error"),
         }
@@ -614,12 +1133,12 @@ mod test {
         thread::sleep(Duration::from_secs(10));
 
         // should ping
-        assert_eq!((), mqtt.handle_outgoing_ping().unwrap());
-        mqtt.handle_incoming_mqtt_packet(Packet::Pingresp).unwrap();
+        assert_eq!((), mqtt.handle_outgoing_ping(Instant::now()).unwrap());
+        mqtt.handle_incoming_mqtt_packet(Packet::Pingresp, Instant::now()).unwrap();
 
         thread::sleep(Duration::from_secs(10));
         // should ping
-        assert_eq!((), mqtt.handle_outgoing_ping().unwrap());
+        assert_eq!((), mqtt.handle_outgoing_ping(Instant::now()).unwrap());
     }
 
     #[test]
@@ -639,7 +1158,7 @@ mod test {
         let _ = mqtt.handle_outgoing_publish(publish.clone());
         let _ = mqtt.handle_outgoing_publish(publish);
 
-        mqtt.handle_previous_session();
+        mqtt.handle_previous_session(Instant::now()).unwrap();
         assert_eq!(mqtt.outgoing_pub.len(), 0);
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
         assert_eq!(mqtt.await_pingresp, false);
@@ -661,7 +1180,7 @@ mod test {
         let _ = mqtt.handle_outgoing_publish(publish.clone());
         let _ = mqtt.handle_outgoing_publish(publish);
 
-        mqtt.handle_previous_session();
+        mqtt.handle_previous_session(Instant::now()).unwrap();
         assert_eq!(mqtt.outgoing_pub.len(), 3);
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
         assert_eq!(mqtt.await_pingresp, false);
@@ -672,19 +1191,19 @@ mod test {
         let mut mqtt = build_mqttstate();
 
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
-        mqtt.handle_outgoing_connect().unwrap();
+        mqtt.handle_outgoing_connect(Instant::now()).unwrap();
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Handshake);
 
         let connack = Connack { session_present: false,
                                 code: ConnectReturnCode::Accepted };
 
-        let _ = mqtt.handle_incoming_connack(connack);
+        let _ = mqtt.handle_incoming_connack(connack, Instant::now());
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Connected);
 
         let connack = Connack { session_present: false,
                                 code: ConnectReturnCode::BadUsernamePassword };
 
-        let _ = mqtt.handle_incoming_connack(connack);
+        let _ = mqtt.handle_incoming_connack(connack, Instant::now());
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
     }
 
@@ -706,7 +1225,7 @@ mod test {
         let connack = Connack { session_present: false,
                                 code: ConnectReturnCode::Accepted };
 
-        mqtt.handle_incoming_connack(connack).unwrap();
+        mqtt.handle_incoming_connack(connack, Instant::now()).unwrap();
         let pubs = mqtt.handle_reconnection();
         assert_eq!(0, pubs.len());
     }
@@ -731,6 +1250,130 @@ mod test {
         assert_eq!(3, pubs.len());
     }
 
+    #[test]
+    fn reconnection_should_retransmit_unacked_publishes_in_order() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_clean_session(false);
+
+        for _ in 0..4 {
+            mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        }
+        mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
+
+        let pkids: Vec<_> = mqtt.handle_reconnection()
+                                .into_iter()
+                                .map(|packet| match packet {
+                                         Packet::Publish(publish) => publish.pkid.unwrap().0,
+                                         packet => panic!("Invalid packet: {:?}", packet),
+                                     })
+                                .collect();
+        assert_eq!(pkids, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn restored_session_should_retransmit_unacked_messages() {
+        let store = Arc::new(MemorySessionStore::new());
+        let opts = MqttOptions::default().set_clean_session(false).set_session_store(store.clone());
+        let mut mqtt = MqttState::new(opts.clone());
+
+        for qos in &[QoS::AtLeastOnce, QoS::AtLeastOnce, QoS::ExactlyOnce, QoS::AtLeastOnce] {
+            mqtt.handle_outgoing_publish(build_outgoing_publish(*qos)).unwrap();
+        }
+        mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(3)).unwrap();
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 9)).unwrap();
+
+        // process restart
+        let mut mqtt = MqttState::new(opts);
+        mqtt.restore_session(store.load().unwrap());
+        let packets: Vec<Packet> = mqtt.handle_reconnection().into_iter().collect();
+        match packets.as_slice() {
+            [Packet::Pubrel(PacketIdentifier(3)), Packet::Publish(first), Packet::Publish(second)] => {
+                assert_eq!(first.pkid, Some(PacketIdentifier(1)));
+                assert_eq!(second.pkid, Some(PacketIdentifier(4)));
+            }
+            packets => panic!("Invalid retransmission: {:?}", packets),
+        }
+        assert_eq!(mqtt.incoming_pub, vec![PacketIdentifier(9)]);
+
+        // new publishes don't reuse restored ids
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(5)));
+    }
+
+    // fails updates once `fail` is set, like a full disk
+    #[derive(Debug, Default)]
+    struct FailingStore {
+        store: MemorySessionStore,
+        fail: AtomicBool,
+    }
+
+    impl SessionStore for FailingStore {
+        fn update(&self, update: &SessionUpdate) -> io::Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.store.update(update)
+        }
+
+        fn clear(&self) -> io::Result<()> {
+            self.store.clear()
+        }
+
+        fn load(&self) -> io::Result<Session> {
+            self.store.load()
+        }
+    }
+
+    #[test]
+    fn failed_persist_should_leave_state_untouched() {
+        let store = Arc::new(FailingStore::default());
+        let opts = MqttOptions::default().set_clean_session(false).set_session_store(store.clone());
+        let mut mqtt = MqttState::new(opts);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        store.fail.store(true, Ordering::SeqCst);
+
+        assert!(mqtt.handle_incoming_puback(PacketIdentifier(1)).is_err());
+        assert!(mqtt.handle_incoming_pubrec(PacketIdentifier(2)).is_err());
+        assert_eq!(mqtt.outgoing_pub.len(), 2);
+        assert!(mqtt.outgoing_rel.is_empty());
+
+        // state and store still agree. acks received after the disk recovers are handled
+        assert_eq!(store.load().unwrap().outgoing_pub.len(), 2);
+        store.fail.store(false, Ordering::SeqCst);
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        assert!(mqtt.outgoing_pub.is_empty());
+        assert_eq!(store.load().unwrap().outgoing_rel, vec![PacketIdentifier(2)]);
+    }
+
+    #[test]
+    fn inflight_should_be_full_till_acks_arrive() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_max_inflight(2);
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert!(!mqtt.is_inflight_full());
+
+        // qos0 publishes aren't acknowledged
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtMostOnce)).unwrap();
+        assert!(!mqtt.is_inflight_full());
+
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        assert!(mqtt.is_inflight_full());
+
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        assert!(!mqtt.is_inflight_full());
+
+        // qos2 publish is in flight till pubcomp
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        assert_eq!(mqtt.inflight(), 1);
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(2)).unwrap();
+        assert_eq!(mqtt.inflight(), 0);
+    }
+
     #[test]
     fn connect_should_respect_options() {
         use mqttoptions::SecurityOptions::UsernamePassword;
@@ -748,7 +1391,7 @@ mod test {
         let mut mqtt = MqttState::new(opts);
 
         assert_eq!(mqtt.connection_status, MqttConnectionStatus::Disconnected);
-        let pkt = mqtt.handle_outgoing_connect().unwrap();
+        let pkt = mqtt.handle_outgoing_connect(Instant::now()).unwrap();
         assert_eq!(pkt,
                    Connect { protocol: Protocol::MQTT(4),
                              keep_alive: 50,
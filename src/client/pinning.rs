@@ -0,0 +1,57 @@
+use mqttoptions::Pin;
+use ring::digest;
+use x509_parser::parse_x509_certificate;
+
+/// Checks if any of the pins matches the der encoded certificate or its public key.
+/// Callers decide which certificates of the chain are trustworthy enough to check
+pub fn pins_match(pins: &[Pin], cert: &[u8]) -> bool {
+    let cert_hash = sha256(cert);
+    let key_hash = subject_public_key_info(cert).map(sha256);
+
+    pins.iter().any(|pin| match *pin {
+                   Pin::Certificate(ref hash) => *hash == cert_hash,
+                   Pin::PublicKey(ref hash) => Some(*hash) == key_hash,
+               })
+}
+
+/// Returns the der encoded `SubjectPublicKeyInfo` of a der encoded x509 certificate
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    match parse_x509_certificate(cert) {
+        Ok((_, cert)) => Some(cert.tbs_certificate.subject_pki.raw),
+        Err(_) => None,
+    }
+}
+
+/// Sha256 of the input. Used to compare pins of both tls backends
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pins_match, sha256, subject_public_key_info};
+    use mqttoptions::Pin;
+
+    const CERT: &[u8] = include_bytes!("../../tests/tlsfiles/server.cert.der");
+    const PUBLIC_KEY: &[u8] = include_bytes!("../../tests/tlsfiles/server.pubkey.der");
+
+    #[test]
+    fn sha256_should_match_known_answers() {
+        let hash = sha256(b"abc");
+        let expected = [0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+                        0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad];
+        assert_eq!(hash, expected);
+    }
+
+    #[test]
+    fn public_key_and_certificate_pins_should_match() {
+        assert_eq!(subject_public_key_info(CERT), Some(PUBLIC_KEY));
+
+        assert!(pins_match(&[Pin::PublicKey(sha256(PUBLIC_KEY))], CERT));
+        assert!(pins_match(&[Pin::Certificate(sha256(CERT))], CERT));
+        assert!(!pins_match(&[Pin::PublicKey(sha256(CERT)), Pin::Certificate([0; 32])], CERT));
+        assert!(!pins_match(&[Pin::PublicKey(sha256(PUBLIC_KEY))], b"garbage"));
+    }
+}
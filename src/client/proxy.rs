@@ -0,0 +1,358 @@
+use base64;
+use error::ConnectError;
+use futures::{
+    future::{self, Either},
+    Async, Future, Poll,
+};
+use mqttoptions::{Proxy, ProxyType};
+use std::io::{ErrorKind, Read};
+use tokio_io::{
+    io::{read_exact, write_all},
+    AsyncRead, AsyncWrite,
+};
+
+/// Proxy responses with headers bigger than this are rejected
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USERNAME_PASSWORD: u8 = 2;
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+/// Asks the proxy at the other end of the stream to open a tunnel to the broker.
+/// Resolves with the same stream once the tunnel is established. Tls and mqtt
+/// handshakes happen on top of the tunnel
+pub fn connect<S>(stream: S, proxy: &Proxy, host: &str, port: u16) -> impl Future<Item = S, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    match proxy.proxy_type() {
+        ProxyType::Http => Either::A(http_connect(stream, host, port, proxy.credentials())),
+        ProxyType::Socks5 => Either::B(socks5_connect(stream, host, port, proxy.credentials())),
+    }
+}
+
+fn http_connect<S>(stream: S,
+                   host: &str,
+                   port: u16,
+                   credentials: Option<(String, String)>)
+                   -> impl Future<Item = S, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some((ref username, ref password)) = credentials {
+        let token = base64::encode(format!("{}:{}", username, password).as_bytes());
+        request += &format!("Proxy-Authorization: Basic {}\r\n", token);
+    }
+
+    request += "\r\n";
+
+    let authenticate = credentials.is_some();
+    write_all(stream, request.into_bytes()).map_err(ConnectError::Io)
+                                           .and_then(|(stream, _request)| ReadHttpResponse { stream: Some(stream),
+                                                                                             buf: Vec::new() })
+                                           .and_then(move |(stream, response)| {
+                                               let status = response.split_whitespace()
+                                                                    .nth(1)
+                                                                    .and_then(|status| status.parse::<u16>().ok());
+
+                                               match status {
+                                                   Some(200..=299) => Ok(stream),
+                                                   Some(407) if authenticate => Err(ConnectError::ProxyAuthenticationFailed),
+                                                   Some(407) => Err(ConnectError::ProxyAuthenticationRequired),
+                                                   Some(status) => {
+                                                       error!("Proxy failed to connect. Status = {}", status);
+                                                       Err(ConnectError::ProxyConnectFailed)
+                                                   }
+                                                   None => Err(ConnectError::InvalidProxyResponse),
+                                               }
+                                           })
+}
+
+fn socks5_connect<S>(stream: S,
+                     host: &str,
+                     port: u16,
+                     credentials: Option<(String, String)>)
+                     -> impl Future<Item = S, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    let greeting = match credentials {
+        Some(_) => vec![SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USERNAME_PASSWORD],
+        None => vec![SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+    };
+
+    // connect command with the broker's domain name. Proxy resolves the name
+    let request = if host.len() > 255 {
+        error!("Host name too long for socks5. Host = {}", host);
+        Err(ConnectError::ProxyConnectFailed)
+    } else {
+        let mut request = vec![SOCKS_VERSION, 1, 0, 3, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.push((port >> 8) as u8);
+        request.push(port as u8);
+        Ok(request)
+    };
+
+    future::result(request).and_then(move |request| {
+        write_all(stream, greeting).and_then(|(stream, _greeting)| read_exact(stream, [0u8; 2]))
+                                   .map_err(ConnectError::Io)
+                                   .and_then(move |(stream, reply)| socks5_authenticate(stream, reply, credentials))
+                                   .and_then(move |stream| {
+                                       write_all(stream, request).and_then(|(stream, _request)| {
+                                                                      read_exact(stream, [0u8; 4])
+                                                                  })
+                                                                 .map_err(ConnectError::Io)
+                                   })
+                                   .and_then(|(stream, reply)| {
+                                       if reply[0] != SOCKS_VERSION {
+                                           return Either::A(future::err(ConnectError::InvalidProxyResponse));
+                                       }
+
+                                       if reply[1] != 0 {
+                                           error!("Socks5 proxy failed to connect. Reply = {}", reply[1]);
+                                           return Either::A(future::err(ConnectError::ProxyConnectFailed));
+                                       }
+
+                                       // bound address and port. Not used
+                                       let address = match reply[3] {
+                                           1 => Either::A(read_exact(stream, vec![0u8; 4 + 2])),
+                                           4 => Either::A(read_exact(stream, vec![0u8; 16 + 2])),
+                                           3 => Either::B(read_exact(stream, [0u8; 1]).and_then(|(stream, len)| {
+                                                                                         let len = len[0] as usize + 2;
+                                                                                         read_exact(stream, vec![0u8; len])
+                                                                                     })),
+                                           _ => return Either::A(future::err(ConnectError::InvalidProxyResponse)),
+                                       };
+
+                                       Either::B(address.map(|(stream, _address)| stream).map_err(ConnectError::Io))
+                                   })
+    })
+}
+
+fn socks5_authenticate<S>(stream: S,
+                          reply: [u8; 2],
+                          credentials: Option<(String, String)>)
+                          -> impl Future<Item = S, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    match (reply, credentials) {
+        ([SOCKS_VERSION, SOCKS_NO_AUTH], _) => Either::A(future::ok(stream)),
+        ([SOCKS_VERSION, SOCKS_USERNAME_PASSWORD], Some((username, password))) => {
+            Either::B(socks5_username_password(stream, username, password))
+        }
+        ([SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS], Some(_)) => {
+            Either::A(future::err(ConnectError::ProxyAuthenticationFailed))
+        }
+        ([SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS], None) | ([SOCKS_VERSION, SOCKS_USERNAME_PASSWORD], None) => {
+            Either::A(future::err(ConnectError::ProxyAuthenticationRequired))
+        }
+        _ => Either::A(future::err(ConnectError::InvalidProxyResponse)),
+    }
+}
+
+/// Username/password authentication of socks5 (RFC 1929)
+fn socks5_username_password<S>(stream: S, username: String, password: String) -> impl Future<Item = S, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    let request = if username.len() > 255 || password.len() > 255 {
+        error!("Socks5 username and password should be at most 255 bytes");
+        Err(ConnectError::ProxyAuthenticationFailed)
+    } else {
+        let mut request = vec![1, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        Ok(request)
+    };
+
+    future::result(request).and_then(|request| {
+                               write_all(stream, request).and_then(|(stream, _request)| read_exact(stream, [0u8; 2]))
+                                                         .map_err(ConnectError::Io)
+                           })
+                           .and_then(|(stream, reply)| match reply {
+                               [1, 0] => Ok(stream),
+                               [1, _] => Err(ConnectError::ProxyAuthenticationFailed),
+                               _ => Err(ConnectError::InvalidProxyResponse),
+                           })
+}
+
+/// Reads the status line and headers of the http response. Reads a byte at a
+/// time to not consume any bytes of the tunnel
+struct ReadHttpResponse<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ReadHttpResponse<S> {
+    type Item = (S, String);
+    type Error = ConnectError;
+
+    fn poll(&mut self) -> Poll<Self::Item, ConnectError> {
+        loop {
+            if self.buf.ends_with(b"\r\n\r\n") {
+                let response = String::from_utf8_lossy(&self.buf).into_owned();
+                let stream = self.stream.take().expect("Polled after completion");
+                return Ok(Async::Ready((stream, response)));
+            }
+
+            if self.buf.len() > MAX_RESPONSE_SIZE {
+                return Err(ConnectError::InvalidProxyResponse);
+            }
+
+            let mut byte = [0u8; 1];
+            let stream = self.stream.as_mut().expect("Polled after completion");
+            match stream.read(&mut byte) {
+                Ok(0) => return Err(ConnectError::InvalidProxyResponse),
+                Ok(_) => self.buf.push(byte[0]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(ConnectError::Io(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::connect;
+    use error::ConnectError;
+    use futures::Future;
+    use mqttoptions::{Proxy, ProxyType};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream as StdTcpStream},
+        thread,
+    };
+    use tokio::{net::TcpStream, runtime::current_thread};
+    use tokio_io::io::read_exact;
+
+    /// Runs the proxy side of the handshake on a thread. Returns the port of the proxy
+    fn proxy_server<F>(handshake: F) -> u16
+        where F: FnOnce(&mut StdTcpStream) + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream);
+        });
+
+        port
+    }
+
+    fn read_http_request(stream: &mut StdTcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut byte = [0u8; 1];
+        while !buf.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            buf.push(byte[0]);
+        }
+
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn tunnel(port: u16, proxy: Proxy) -> Result<Vec<u8>, ConnectError> {
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let future = TcpStream::connect(&addr).map_err(ConnectError::Io)
+                                              .and_then(move |stream| connect(stream, &proxy, "broker.example.com", 8883))
+                                              .and_then(|stream| read_exact(stream, vec![0u8; 2]).map_err(ConnectError::Io))
+                                              .map(|(_stream, data)| data);
+
+        rt.block_on(future)
+    }
+
+    #[test]
+    fn http_proxy_should_tunnel_with_basic_auth() {
+        let port = proxy_server(|stream| {
+                                    let request = read_http_request(stream);
+                                    assert!(request.starts_with("CONNECT broker.example.com:8883 HTTP/1.1\r\n"));
+                                    // base64 of "user:pass"
+                                    assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+
+                                    stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+                                    stream.write_all(&[0xD0, 0x00]).unwrap();
+                                });
+
+        let proxy = Proxy::new(ProxyType::Http, "127.0.0.1", port).set_credentials("user", "pass");
+        assert_eq!(tunnel(port, proxy).unwrap(), vec![0xD0, 0x00]);
+    }
+
+    #[test]
+    fn http_proxy_auth_failures_should_be_reported() {
+        let rejecting_server = || {
+            proxy_server(|stream| {
+                             read_http_request(stream);
+                             stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap();
+                         })
+        };
+
+        let port = rejecting_server();
+        match tunnel(port, Proxy::new(ProxyType::Http, "127.0.0.1", port)) {
+            Err(ConnectError::ProxyAuthenticationRequired) => (),
+            v => panic!("Should throw authentication required error. Got = {:?}", v),
+        }
+
+        let port = rejecting_server();
+        match tunnel(port, Proxy::new(ProxyType::Http, "127.0.0.1", port).set_credentials("user", "wrong")) {
+            Err(ConnectError::ProxyAuthenticationFailed) => (),
+            v => panic!("Should throw authentication failed error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn socks5_proxy_should_tunnel_with_username_password() {
+        let port = proxy_server(|stream| {
+                                    let mut greeting = [0u8; 4];
+                                    stream.read_exact(&mut greeting).unwrap();
+                                    assert_eq!(greeting, [5, 2, 0, 2]);
+                                    stream.write_all(&[5, 2]).unwrap();
+
+                                    let mut auth = [0u8; 11];
+                                    stream.read_exact(&mut auth).unwrap();
+                                    assert_eq!(&auth, b"\x01\x04user\x04pass");
+                                    stream.write_all(&[1, 0]).unwrap();
+
+                                    let mut request = vec![0u8; 5 + 18 + 2];
+                                    stream.read_exact(&mut request).unwrap();
+                                    assert_eq!(&request[..5], &[5, 1, 0, 3, 18]);
+                                    assert_eq!(&request[5..23], b"broker.example.com");
+                                    assert_eq!(&request[23..], &[0x22, 0xB3]);
+
+                                    stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x22, 0xB3]).unwrap();
+                                    stream.write_all(&[0xD0, 0x00]).unwrap();
+                                });
+
+        let proxy = Proxy::new(ProxyType::Socks5, "127.0.0.1", port).set_credentials("user", "pass");
+        assert_eq!(tunnel(port, proxy).unwrap(), vec![0xD0, 0x00]);
+    }
+
+    #[test]
+    fn socks5_auth_failures_should_be_reported() {
+        let port = proxy_server(|stream| {
+                                    let mut greeting = [0u8; 4];
+                                    stream.read_exact(&mut greeting).unwrap();
+                                    stream.write_all(&[5, 2]).unwrap();
+
+                                    let mut auth = [0u8; 12];
+                                    stream.read_exact(&mut auth).unwrap();
+                                    stream.write_all(&[1, 1]).unwrap();
+                                });
+
+        let proxy = Proxy::new(ProxyType::Socks5, "127.0.0.1", port).set_credentials("user", "wrong");
+        match tunnel(port, proxy) {
+            Err(ConnectError::ProxyAuthenticationFailed) => (),
+            v => panic!("Should throw authentication failed error. Got = {:?}", v),
+        }
+
+        let port = proxy_server(|stream| {
+                                    let mut greeting = [0u8; 3];
+                                    stream.read_exact(&mut greeting).unwrap();
+                                    stream.write_all(&[5, 0xFF]).unwrap();
+                                });
+
+        match tunnel(port, Proxy::new(ProxyType::Socks5, "127.0.0.1", port)) {
+            Err(ConnectError::ProxyAuthenticationRequired) => (),
+            v => panic!("Should throw authentication required error. Got = {:?}", v),
+        }
+    }
+}
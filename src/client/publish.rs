@@ -0,0 +1,131 @@
+use error::ClientError;
+use mqtt311::{Publish, QoS};
+use std::sync::Arc;
+
+/// Maximum length of a topic name. Topic names are encoded
+/// with a 2 byte length prefix
+const MAX_TOPIC_LEN: usize = 65_535;
+
+/// Builds a publish with all the options of a mqtt publish packet.
+/// Topic name is validated before the publish is handed over to the
+/// event loop
+#[derive(Clone, Debug)]
+pub struct PublishBuilder {
+    topic: String,
+    qos: QoS,
+    payload: Vec<u8>,
+    retain: bool,
+    dup: bool,
+}
+
+impl PublishBuilder {
+    pub fn new<S, V>(topic: S, qos: QoS, payload: V) -> PublishBuilder
+    where S: Into<String>, V: Into<Vec<u8>>
+    {
+        PublishBuilder { topic: topic.into(),
+                         qos,
+                         payload: payload.into(),
+                         retain: false,
+                         dup: false }
+    }
+
+    /// Asks the broker to retain this message as the last known good value of the topic
+    pub fn set_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Marks the publish as a redelivery of an earlier attempt
+    pub fn set_dup(mut self, dup: bool) -> Self {
+        self.dup = dup;
+        self
+    }
+
+    pub fn build(self) -> Result<Publish, ClientError> {
+        validate_topic_name(&self.topic)?;
+
+        let publish = Publish { dup: self.dup,
+                                qos: self.qos,
+                                retain: self.retain,
+                                topic_name: self.topic,
+                                pkid: None,
+                                payload: Arc::new(self.payload) };
+
+        Ok(publish)
+    }
+}
+
+/// Checks if the topic is a valid topic name to publish on.
+/// Wildcards are only allowed in topic filters
+pub fn validate_topic_name(topic: &str) -> Result<(), ClientError> {
+    if topic.is_empty() {
+        return Err(ClientError::EmptyTopic);
+    }
+
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(ClientError::TopicTooLong);
+    }
+
+    if topic.contains('\0') {
+        return Err(ClientError::NullCharacterInTopic);
+    }
+
+    if topic.contains('+') || topic.contains('#') {
+        return Err(ClientError::WildcardInTopic);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_topic_name, PublishBuilder};
+    use error::ClientError;
+    use mqtt311::QoS;
+
+    #[test]
+    fn builder_should_set_retain_and_dup() {
+        let publish = PublishBuilder::new("hello/world", QoS::AtLeastOnce, vec![1, 2, 3]).set_retain(true)
+                                                                                         .set_dup(true)
+                                                                                         .build()
+                                                                                         .unwrap();
+
+        assert!(publish.retain);
+        assert!(publish.dup);
+        assert_eq!(publish.pkid, None);
+        assert_eq!(publish.topic_name, "hello/world");
+    }
+
+    #[test]
+    fn invalid_topic_names_should_be_rejected() {
+        match validate_topic_name("") {
+            Err(ClientError::EmptyTopic) => (),
+            v => panic!("Should throw empty topic error. Got = {:?}", v),
+        }
+
+        match validate_topic_name("hello/+/world") {
+            Err(ClientError::WildcardInTopic) => (),
+            v => panic!("Should throw wildcard error. Got = {:?}", v),
+        }
+
+        match validate_topic_name("hello/#") {
+            Err(ClientError::WildcardInTopic) => (),
+            v => panic!("Should throw wildcard error. Got = {:?}", v),
+        }
+
+        match validate_topic_name("hello\0world") {
+            Err(ClientError::NullCharacterInTopic) => (),
+            v => panic!("Should throw null character error. Got = {:?}", v),
+        }
+
+        let topic = "a".repeat(65_536);
+        match validate_topic_name(&topic) {
+            Err(ClientError::TopicTooLong) => (),
+            v => panic!("Should throw topic length error. Got = {:?}", v),
+        }
+
+        let topic = "a".repeat(65_535);
+        validate_topic_name(&topic).unwrap();
+        validate_topic_name("/hello/world/").unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+use crc32fast;
+use mqtt311::{MqttRead, MqttWrite, Packet, PacketIdentifier, Publish};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use MqttOptions;
+
+// log is rewritten with only the live state once it has these many stale records
+const COMPACT_AFTER: usize = 1024;
+
+// direction of the packet in a record of the file store
+const OUTGOING: u8 = 0;
+const INCOMING: u8 = 1;
+
+// body length and crc of the body before every record
+const RECORD_HEADER_LEN: usize = 8;
+// direction and the smallest packet (fixed header and packet id)
+const MIN_RECORD_BODY_LEN: usize = 5;
+
+/// Qos state of a persistent session
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    /// Qos1 & qos2 publishes waiting for puback or pubrec, in the order they were sent
+    pub outgoing_pub: Vec<Publish>,
+    /// Qos2 publishes waiting for pubcomp
+    pub outgoing_rel: Vec<PacketIdentifier>,
+    /// Qos2 publishes of the broker waiting for pubrel
+    pub incoming_pub: Vec<PacketIdentifier>,
+}
+
+/// Change to the qos state of a session
+#[derive(Clone, Debug)]
+pub enum SessionUpdate {
+    /// Qos1 or qos2 publish is about to be sent
+    Publish(Publish),
+    /// Broker acknowledged a qos1 publish
+    PubAck(PacketIdentifier),
+    /// Broker received a qos2 publish. It waits for pubcomp now
+    PubRec(PacketIdentifier),
+    /// Broker completed a qos2 publish
+    PubComp(PacketIdentifier),
+    /// Qos2 publish is received from the broker
+    IncomingPublish(PacketIdentifier),
+    /// Broker released a received qos2 publish
+    PubRel(PacketIdentifier),
+}
+
+/// Persists the qos state of `clean_session(false)` sessions so that unacknowledged
+/// messages survive process restarts. The event loop updates the store before acting
+/// on a change and loads it when the client starts
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    fn update(&self, update: &SessionUpdate) -> io::Result<()>;
+
+    /// Called when the broker starts a clean session
+    fn clear(&self) -> io::Result<()>;
+
+    fn load(&self) -> io::Result<Session>;
+}
+
+impl Session {
+    /// Applies the update to the session. Stores can keep the live session with this
+    pub fn apply(&mut self, update: &SessionUpdate) {
+        match *update {
+            SessionUpdate::Publish(ref publish) => {
+                self.outgoing_pub.retain(|p| p.pkid != publish.pkid);
+                self.outgoing_pub.push(publish.clone());
+            }
+            SessionUpdate::PubAck(pkid) => self.outgoing_pub.retain(|p| p.pkid != Some(pkid)),
+            SessionUpdate::PubRec(pkid) => {
+                self.outgoing_pub.retain(|p| p.pkid != Some(pkid));
+                if !self.outgoing_rel.contains(&pkid) {
+                    self.outgoing_rel.push(pkid);
+                }
+            }
+            SessionUpdate::PubComp(pkid) => self.outgoing_rel.retain(|p| *p != pkid),
+            SessionUpdate::IncomingPublish(pkid) => {
+                if !self.incoming_pub.contains(&pkid) {
+                    self.incoming_pub.push(pkid);
+                }
+            }
+            SessionUpdate::PubRel(pkid) => self.incoming_pub.retain(|p| *p != pkid),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.outgoing_pub.len() + self.outgoing_rel.len() + self.incoming_pub.len()
+    }
+
+    /// Updates which recreate this session
+    fn updates(&self) -> Vec<SessionUpdate> {
+        let publishes = self.outgoing_pub.iter().cloned().map(SessionUpdate::Publish);
+        let rels = self.outgoing_rel.iter().cloned().map(SessionUpdate::PubRec);
+        let incoming = self.incoming_pub.iter().cloned().map(SessionUpdate::IncomingPublish);
+        publishes.chain(rels).chain(incoming).collect()
+    }
+}
+
+/// Session of the last run, if the options ask for a persistent session with a store
+pub(crate) fn load_session(opts: &MqttOptions) -> io::Result<Session> {
+    match opts.session_store() {
+        Some(ref store) if !opts.clean_session() => store.load(),
+        _ => Ok(Session::default()),
+    }
+}
+
+/// Keeps the session in memory. Survives restarts of the client (but not the process)
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    session: Mutex<Session>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn update(&self, update: &SessionUpdate) -> io::Result<()> {
+        self.session.lock().unwrap().apply(update);
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        *self.session.lock().unwrap() = Session::default();
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Session> {
+        Ok(self.session.lock().unwrap().clone())
+    }
+}
+
+/// Appends every update to a file and syncs it to disk so that the session survives
+/// reboots and power loss. The file is replayed when the store is opened and is
+/// compacted to the live state once it has enough stale records. Replay stops at the
+/// first record which is incomplete or fails its checksum (a write torn by a crash or
+/// a tail zero filled by the filesystem) and the file is truncated there
+#[derive(Debug)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    log: Mutex<SessionLog>,
+}
+
+#[derive(Debug)]
+struct SessionLog {
+    file: File,
+    session: Session,
+    records: usize,
+    // partial record couldn't be cut off after a failed write. log is rewritten before the next append
+    torn: bool,
+}
+
+impl FileSessionStore {
+    /// Opens (or creates) the session file at this path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileSessionStore> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut session = Session::default();
+        let mut records = 0;
+        let mut offset = 0;
+        while offset < buf.len() {
+            match read_record(&buf[offset..]) {
+                Some((update, len)) => {
+                    session.apply(&update);
+                    records += 1;
+                    offset += len;
+                }
+                None => {
+                    warn!("Discarding {} bytes of the session file from the bad record at offset {}",
+                          buf.len() - offset,
+                          offset);
+                    file.set_len(offset as u64)?;
+                    file.sync_data()?;
+                    break;
+                }
+            }
+        }
+
+        let log = SessionLog { file, session, records, torn: false };
+        Ok(FileSessionStore { path, log: Mutex::new(log) })
+    }
+
+    /// Rewrites the file with only the live state. Replaces the old file atomically
+    fn compact(&self, log: &mut SessionLog) -> io::Result<()> {
+        let updates = log.session.updates();
+        let mut buf = Vec::new();
+        for update in &updates {
+            write_record(&mut buf, update)?;
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        log.file = OpenOptions::new().append(true).open(&self.path)?;
+        log.records = updates.len();
+        log.torn = false;
+        Ok(())
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn update(&self, update: &SessionUpdate) -> io::Result<()> {
+        let mut buf = Vec::new();
+        write_record(&mut buf, update)?;
+
+        let mut log = self.log.lock().unwrap();
+        if log.torn {
+            self.compact(&mut log)?;
+        }
+
+        {
+            let log = &mut *log;
+            append(&mut log.file, &buf, &mut log.torn)?;
+        }
+        log.session.apply(update);
+        log.records += 1;
+
+        if log.records > COMPACT_AFTER && log.records > 2 * log.session.len() {
+            self.compact(&mut log)?;
+        }
+
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.file.set_len(0)?;
+        log.file.sync_data()?;
+        log.session = Session::default();
+        log.records = 0;
+        log.torn = false;
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Session> {
+        Ok(self.log.lock().unwrap().session.clone())
+    }
+}
+
+/// File operations of the session log. Lets tests inject failed writes
+trait LogFile: Write {
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Appends a record durably. A failed write is cut off again so that the following
+/// records aren't appended behind a torn one (and thrown away with it on the next open).
+/// Sets `torn` if that isn't possible either
+fn append<F: LogFile>(file: &mut F, buf: &[u8], torn: &mut bool) -> io::Result<()> {
+    let len = file.len()?;
+    let e = match file.write_all(buf).and_then(|_| file.sync_data()) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if let Err(err) = file.set_len(len).and_then(|_| file.sync_data()) {
+        error!("Unable to discard the partial session record. Error = {:?}", err);
+        *torn = true;
+    }
+
+    Err(e)
+}
+
+/// Makes the rename of a file durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Record is the length and crc32 of the body (big endian u32s) followed by the body.
+/// Body is the direction byte followed by the mqtt packet which caused the update
+fn write_record(buf: &mut Vec<u8>, update: &SessionUpdate) -> io::Result<()> {
+    let (direction, packet) = match *update {
+        SessionUpdate::Publish(ref publish) => (OUTGOING, Packet::Publish(publish.clone())),
+        SessionUpdate::PubAck(pkid) => (OUTGOING, Packet::Puback(pkid)),
+        SessionUpdate::PubRec(pkid) => (OUTGOING, Packet::Pubrec(pkid)),
+        SessionUpdate::PubComp(pkid) => (OUTGOING, Packet::Pubcomp(pkid)),
+        // replies of the client to incoming qos2 publishes
+        SessionUpdate::IncomingPublish(pkid) => (INCOMING, Packet::Pubrec(pkid)),
+        SessionUpdate::PubRel(pkid) => (INCOMING, Packet::Pubcomp(pkid)),
+    };
+
+    let mut stream = Cursor::new(Vec::new());
+    if let Err(e) = stream.write_packet(&packet) {
+        error!("Session record encode error. Error = {:?}", e);
+        return Err(io::Error::new(ErrorKind::Other, "Unable to encode session record"));
+    }
+
+    let mut body = vec![direction];
+    body.extend(stream.get_ref());
+
+    buf.extend_from_slice(&be_bytes(body.len() as u32));
+    buf.extend_from_slice(&be_bytes(crc32fast::hash(&body)));
+    buf.extend(body);
+    Ok(())
+}
+
+/// Reads a record and returns its length. Returns `None` if the record is incomplete,
+/// fails the checksum or doesn't hold an update
+fn read_record(buf: &[u8]) -> Option<(SessionUpdate, usize)> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+
+    let len = be_u32(&buf[0..4]) as usize;
+    let crc = be_u32(&buf[4..8]);
+    if len < MIN_RECORD_BODY_LEN || buf.len() - RECORD_HEADER_LEN < len {
+        return None;
+    }
+
+    let body = &buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len];
+    if crc32fast::hash(body) != crc {
+        return None;
+    }
+
+    let mut packet_buf = &body[1..];
+    let packet = match packet_buf.read_packet_with_len() {
+        Ok((packet, _)) => packet,
+        Err(e) => {
+            error!("Session record decode error. Error = {:?}", e);
+            return None;
+        }
+    };
+
+    let update = match (body[0], packet) {
+        (OUTGOING, Packet::Publish(publish)) => SessionUpdate::Publish(publish),
+        (OUTGOING, Packet::Puback(pkid)) => SessionUpdate::PubAck(pkid),
+        (OUTGOING, Packet::Pubrec(pkid)) => SessionUpdate::PubRec(pkid),
+        (OUTGOING, Packet::Pubcomp(pkid)) => SessionUpdate::PubComp(pkid),
+        (INCOMING, Packet::Pubrec(pkid)) => SessionUpdate::IncomingPublish(pkid),
+        (INCOMING, Packet::Pubcomp(pkid)) => SessionUpdate::PubRel(pkid),
+        _ => return None,
+    };
+
+    Some((update, RECORD_HEADER_LEN + len))
+}
+
+fn be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    (u32::from(buf[0]) << 24) | (u32::from(buf[1]) << 16) | (u32::from(buf[2]) << 8) | u32::from(buf[3])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append, write_record, FileSessionStore, LogFile, MemorySessionStore, SessionStore, SessionUpdate};
+    use mqtt311::{PacketIdentifier, Publish, QoS};
+    use std::{
+        env,
+        fs::{self, File, OpenOptions},
+        io::{self, ErrorKind, Write},
+        process,
+        sync::Arc,
+    };
+
+    /// Writes half of the record and fails like a full disk
+    struct FailingFile {
+        file: File,
+        truncate: bool,
+    }
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write_all(&buf[..buf.len() / 2])?;
+            Err(io::Error::new(ErrorKind::Other, "No space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl LogFile for FailingFile {
+        fn len(&self) -> io::Result<u64> {
+            self.file.len()
+        }
+
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            if self.truncate {
+                LogFile::set_len(&self.file, len)
+            } else {
+                Err(io::Error::new(ErrorKind::Other, "Read-only file system"))
+            }
+        }
+
+        fn sync_data(&self) -> io::Result<()> {
+            LogFile::sync_data(&self.file)
+        }
+    }
+
+    fn publish(pkid: u16, qos: QoS) -> Publish {
+        Publish { dup: false,
+                  qos,
+                  retain: false,
+                  pkid: Some(PacketIdentifier(pkid)),
+                  topic_name: "hello/world".to_owned(),
+                  payload: Arc::new(vec![1, 2, 3]) }
+    }
+
+    fn updates() -> Vec<SessionUpdate> {
+        vec![SessionUpdate::Publish(publish(1, QoS::AtLeastOnce)),
+             SessionUpdate::Publish(publish(2, QoS::ExactlyOnce)),
+             SessionUpdate::Publish(publish(3, QoS::AtLeastOnce)),
+             SessionUpdate::PubAck(PacketIdentifier(1)),
+             SessionUpdate::PubRec(PacketIdentifier(2)),
+             SessionUpdate::IncomingPublish(PacketIdentifier(7)),
+             SessionUpdate::IncomingPublish(PacketIdentifier(8)),
+             SessionUpdate::PubRel(PacketIdentifier(7))]
+    }
+
+    fn assert_session<S: SessionStore>(store: &S) {
+        let session = store.load().unwrap();
+        let pkids: Vec<_> = session.outgoing_pub.iter().map(|p| p.pkid).collect();
+        assert_eq!(pkids, vec![Some(PacketIdentifier(3))]);
+        assert_eq!(session.outgoing_pub[0].payload.as_ref(), &vec![1, 2, 3]);
+        assert_eq!(session.outgoing_rel, vec![PacketIdentifier(2)]);
+        assert_eq!(session.incoming_pub, vec![PacketIdentifier(8)]);
+    }
+
+    #[test]
+    fn memory_store_should_apply_updates() {
+        let store = MemorySessionStore::new();
+        for update in updates() {
+            store.update(&update).unwrap();
+        }
+
+        assert_session(&store);
+        store.clear().unwrap();
+        assert!(store.load().unwrap().outgoing_pub.is_empty());
+    }
+
+    #[test]
+    fn file_store_should_survive_reopen_and_partial_writes() {
+        let path = env::temp_dir().join(format!("rumqtt-session-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        for update in updates() {
+            store.update(&update).unwrap();
+        }
+        drop(store);
+
+        // crash in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 20, 0x12, 0x34, 0x56, 0x78, 0, 0x32, 0x12, 0x00]).unwrap();
+        drop(file);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        assert_session(&store);
+
+        // torn record is thrown away and new records are appended after the good ones
+        store.update(&SessionUpdate::PubAck(PacketIdentifier(3))).unwrap();
+        let store = FileSessionStore::open(&path).unwrap();
+        assert!(store.load().unwrap().outgoing_pub.is_empty());
+
+        store.clear().unwrap();
+        let store = FileSessionStore::open(&path).unwrap();
+        assert!(store.load().unwrap().outgoing_rel.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_should_truncate_at_first_corrupted_record() {
+        let path = env::temp_dir().join(format!("rumqtt-session-corrupted-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        for update in updates() {
+            store.update(&update).unwrap();
+        }
+        let good_len = fs::metadata(&path).unwrap().len();
+        store.update(&SessionUpdate::PubAck(PacketIdentifier(3))).unwrap();
+        drop(store);
+
+        // filesystem zero filled the tail after a power loss
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0; 64]).unwrap();
+        drop(file);
+        let store = FileSessionStore::open(&path).unwrap();
+        assert!(store.load().unwrap().outgoing_pub.is_empty());
+        drop(store);
+
+        // bit flip in the payload of the last record
+        let mut buf = fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+        fs::write(&path, &buf).unwrap();
+
+        let store = FileSessionStore::open(&path).unwrap();
+        assert_session(&store);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_should_compact_stale_records() {
+        let path = env::temp_dir().join(format!("rumqtt-session-compact-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        for i in 1..2000 {
+            store.update(&SessionUpdate::Publish(publish(i, QoS::AtLeastOnce))).unwrap();
+            store.update(&SessionUpdate::PubAck(PacketIdentifier(i))).unwrap();
+        }
+        store.update(&SessionUpdate::Publish(publish(2000, QoS::AtLeastOnce))).unwrap();
+
+        let records = store.log.lock().unwrap().records;
+        assert!(records <= 1024, "Log should be compacted. Records = {}", records);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        let session = store.load().unwrap();
+        assert_eq!(session.outgoing_pub.len(), 1);
+        assert_eq!(session.outgoing_pub[0].pkid, Some(PacketIdentifier(2000)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_should_not_append_behind_a_failed_write() {
+        let path = env::temp_dir().join(format!("rumqtt-session-failed-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        let updates = updates();
+        let (head, tail) = updates.split_at(4);
+        for update in head {
+            store.update(update).unwrap();
+        }
+
+        let mut buf = Vec::new();
+        write_record(&mut buf, &SessionUpdate::PubAck(PacketIdentifier(3))).unwrap();
+
+        // partial record is cut off again
+        {
+            let mut log = store.log.lock().unwrap();
+            let file = log.file.try_clone().unwrap();
+            let mut failing = FailingFile { file, truncate: true };
+            match append(&mut failing, &buf, &mut log.torn) {
+                Err(ref e) if e.kind() == ErrorKind::Other => (),
+                v => panic!("Expected failed write. Got = {:?}", v),
+            }
+            assert!(!log.torn);
+        }
+
+        for update in &tail[..2] {
+            store.update(update).unwrap();
+        }
+
+        // partial record can't be cut off. log is rewritten before the next append
+        {
+            let mut log = store.log.lock().unwrap();
+            let file = log.file.try_clone().unwrap();
+            let mut failing = FailingFile { file, truncate: false };
+            match append(&mut failing, &buf, &mut log.torn) {
+                Err(ref e) if e.kind() == ErrorKind::Other => (),
+                v => panic!("Expected failed write. Got = {:?}", v),
+            }
+            assert!(log.torn);
+        }
+
+        for update in &tail[2..] {
+            store.update(update).unwrap();
+        }
+        assert!(!store.log.lock().unwrap().torn);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        assert_session(&store);
+        assert_eq!(store.log.lock().unwrap().records, 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
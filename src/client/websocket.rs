@@ -0,0 +1,562 @@
+use base64;
+use bytes::BytesMut;
+use error::ConnectError;
+use futures::{Async, Future, Poll};
+use mqttoptions::random_u64;
+use ring::digest;
+use std::{
+    cmp,
+    io::{self, ErrorKind, Read, Write},
+};
+use tokio_io::{io::write_all, AsyncRead, AsyncWrite};
+
+/// Appended to the handshake key to compute the accept key (RFC 6455)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Handshake responses bigger than this are rejected
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+/// Encoded frames aren't buffered beyond this when the stream isn't writable
+const MAX_PENDING_WRITE: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Mqtt over websockets. Writes are sent as binary frames and payloads of incoming
+/// binary frames are read back as a continuous byte stream. Websocket frame
+/// boundaries don't have to match mqtt packet boundaries, so `MqttCodec` framing
+/// works unchanged on top of this stream. Incoming frames bigger than the maximum
+/// incoming packet size are rejected before they are buffered
+pub struct WebSocketStream<S> {
+    stream: S,
+    max_frame_size: usize,
+    // set once a close frame is received. reads return eof after that
+    closed: bool,
+    // raw bytes read from the stream which aren't decoded yet
+    read_buf: BytesMut,
+    // payload of decoded frames which isn't read yet
+    payload: BytesMut,
+    // encoded frames which aren't written to the stream yet
+    write_buf: Vec<u8>,
+}
+
+/// Value of the host header of the upgrade request. Default ports of ws and wss are
+/// left out like browsers do. Aws sigv4 signs this exact value
+pub fn host_header(host: &str, port: u16) -> String {
+    match port {
+        80 | 443 => host.to_owned(),
+        port => format!("{}:{}", host, port),
+    }
+}
+
+/// Performs the websocket upgrade handshake with `mqtt` subprotocol on the stream.
+/// `host` is the value of the host header
+pub fn handshake<S>(stream: S,
+                    host: &str,
+                    path: &str,
+                    headers: Vec<(String, String)>,
+                    max_frame_size: usize)
+                    -> impl Future<Item = WebSocketStream<S>, Error = ConnectError>
+    where S: AsyncRead + AsyncWrite
+{
+    let mut key = [0u8; 16];
+    for byte in key.iter_mut() {
+        *byte = random_u64() as u8;
+    }
+
+    let key = base64::encode(&key);
+    let accept = accept_key(&key);
+
+    let mut request = format!("GET {} HTTP/1.1\r\n\
+                               Host: {}\r\n\
+                               Upgrade: websocket\r\n\
+                               Connection: Upgrade\r\n\
+                               Sec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\n\
+                               Sec-WebSocket-Protocol: mqtt\r\n",
+                              path, host, key);
+
+    for (name, value) in headers {
+        request += &format!("{}: {}\r\n", name, value);
+    }
+
+    request += "\r\n";
+
+    write_all(stream, request.into_bytes()).map_err(ConnectError::Io)
+                                           .and_then(|(stream, _request)| ReadResponse { stream: Some(stream),
+                                                                                         buf: Vec::new() })
+                                           .and_then(move |(stream, response, rest)| {
+                                               validate_response(&response, &accept)?;
+                                               Ok(WebSocketStream::new(stream, &rest, max_frame_size))
+                                           })
+}
+
+impl<S> WebSocketStream<S> {
+    fn new(stream: S, read: &[u8], max_frame_size: usize) -> WebSocketStream<S> {
+        WebSocketStream { stream,
+                          max_frame_size,
+                          closed: false,
+                          read_buf: BytesMut::from(read),
+                          payload: BytesMut::new(),
+                          write_buf: Vec::new() }
+    }
+
+    /// Decodes the next complete frame in the read buffer. Fails as soon as the header
+    /// of a frame which is too big or masked (rfc 6455 section 5.1) is available
+    fn decode_frame(&mut self) -> io::Result<Option<(u8, BytesMut)>> {
+        let (opcode, mask, header_len, payload_len) = match frame_header(&self.read_buf) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        if mask.is_some() {
+            error!("Masked websocket frame from the server");
+            return Err(io::Error::new(ErrorKind::InvalidData, "Masked websocket frame from the server"));
+        }
+
+        let frame_len = match header_len.checked_add(payload_len) {
+            Some(len) if payload_len <= self.max_frame_size => len,
+            _ => {
+                error!("Websocket frame payload of {} bytes exceeds maximum of {}", payload_len, self.max_frame_size);
+                return Err(io::Error::new(ErrorKind::InvalidData, "Websocket frame too big"));
+            }
+        };
+
+        if self.read_buf.len() < frame_len {
+            return Ok(None);
+        }
+
+        let payload = self.read_buf.split_to(frame_len).split_off(header_len);
+        Ok(Some((opcode, payload)))
+    }
+
+    /// Encodes a masked frame (clients should always mask) into the write buffer
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let buf = &mut self.write_buf;
+        let len = payload.len();
+
+        buf.push(0x80 | opcode);
+        if len < 126 {
+            buf.push(0x80 | len as u8);
+        } else if len <= 65_535 {
+            buf.push(0x80 | 126);
+            buf.push((len >> 8) as u8);
+            buf.push(len as u8);
+        } else {
+            buf.push(0x80 | 127);
+            for i in (0..8).rev() {
+                buf.push((len as u64 >> (8 * i)) as u8);
+            }
+        }
+
+        let random = random_u64();
+        let mask = [random as u8, (random >> 8) as u8, (random >> 16) as u8, (random >> 24) as u8];
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    }
+}
+
+impl<S: Write> WebSocketStream<S> {
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            let n = self.stream.write(&self.write_buf)?;
+            if n == 0 {
+                return Err(io::Error::new(ErrorKind::WriteZero, "Failed to write websocket frame"));
+            }
+
+            self.write_buf.drain(..n);
+        }
+
+        Ok(())
+    }
+
+    // errors are returned by the next write or flush
+    fn try_write_pending(&mut self) -> io::Result<()> {
+        match self.write_pending() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            v => v,
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if !self.payload.is_empty() {
+                let len = cmp::min(buf.len(), self.payload.len());
+                buf[..len].copy_from_slice(&self.payload.split_to(len));
+                return Ok(len);
+            }
+
+            if self.closed {
+                return Ok(0);
+            }
+
+            match self.decode_frame()? {
+                Some((OPCODE_BINARY, payload)) | Some((OPCODE_CONTINUATION, payload)) => {
+                    self.payload.extend_from_slice(&payload)
+                }
+                Some((OPCODE_PING, payload)) => {
+                    self.encode_frame(OPCODE_PONG, &payload);
+                    self.try_write_pending()?;
+                }
+                Some((OPCODE_PONG, _)) => (),
+                // echo the status code back to complete the closing handshake
+                Some((OPCODE_CLOSE, payload)) => {
+                    self.closed = true;
+                    let status = &payload[..cmp::min(payload.len(), 2)];
+                    self.encode_frame(OPCODE_CLOSE, status);
+                    self.try_write_pending()?;
+                    return Ok(0);
+                }
+                Some((opcode, _)) => {
+                    error!("Unexpected websocket opcode {}", opcode);
+                    return Err(io::Error::new(ErrorKind::InvalidData, "Unexpected websocket opcode"));
+                }
+                None => {
+                    let mut chunk = [0u8; 4096];
+                    let n = self.stream.read(&mut chunk)?;
+                    if n == 0 {
+                        return Ok(0);
+                    }
+
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for WebSocketStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_buf.len() >= MAX_PENDING_WRITE {
+            self.write_pending()?;
+        }
+
+        self.encode_frame(OPCODE_BINARY, buf);
+        self.try_write_pending()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for WebSocketStream<S> {}
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for WebSocketStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.write_pending() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        }
+
+        self.stream.shutdown()
+    }
+}
+
+/// Reads the http response of the handshake. Resolves with the stream, the response
+/// and the bytes read after the response (frames sent right after the handshake)
+struct ReadResponse<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ReadResponse<S> {
+    type Item = (S, String, Vec<u8>);
+    type Error = ConnectError;
+
+    fn poll(&mut self) -> Poll<Self::Item, ConnectError> {
+        loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let rest = self.buf.split_off(end + 4);
+                let response = String::from_utf8_lossy(&self.buf).into_owned();
+                let stream = self.stream.take().expect("Polled after completion");
+                return Ok(Async::Ready((stream, response, rest)));
+            }
+
+            if self.buf.len() > MAX_RESPONSE_SIZE {
+                return Err(ConnectError::WebSocketHandshake("Response too big"));
+            }
+
+            let mut chunk = [0u8; 1024];
+            let stream = self.stream.as_mut().expect("Polled after completion");
+            let n = match stream.read(&mut chunk) {
+                Ok(0) => return Err(ConnectError::WebSocketHandshake("Connection closed")),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(ConnectError::Io(e)),
+            };
+
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn validate_response(response: &str, accept: &str) -> Result<(), ConnectError> {
+    let mut lines = response.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 101") {
+        error!("Websocket upgrade failed. Status = {}", status);
+        return Err(ConnectError::WebSocketHandshake("Unexpected response status"));
+    }
+
+    let (mut upgrade, mut accepted, mut protocol) = (false, false, false);
+    for line in lines {
+        if let Some(i) = line.find(':') {
+            let value = line[i + 1..].trim();
+            match line[..i].trim().to_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-accept" => accepted = value == accept,
+                "sec-websocket-protocol" => protocol = value == "mqtt",
+                _ => (),
+            }
+        }
+    }
+
+    match (upgrade, accepted, protocol) {
+        (false, _, _) => Err(ConnectError::WebSocketHandshake("Connection not upgraded")),
+        (_, false, _) => Err(ConnectError::WebSocketHandshake("Invalid accept key")),
+        (_, _, false) => Err(ConnectError::WebSocketHandshake("Mqtt subprotocol not accepted")),
+        _ => Ok(()),
+    }
+}
+
+/// Parses (opcode, masking key, header length, payload length) of the frame at the
+/// start of the buffer. Returns `None` if the complete header isn't available yet
+fn frame_header(buf: &[u8]) -> Option<(u8, Option<[u8; 4]>, usize, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut header_len) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => ((buf[2] as usize) << 8 | buf[3] as usize, 4),
+        127 if buf.len() >= 10 => {
+            let len = buf[2..10].iter().fold(0u64, |len, byte| len << 8 | u64::from(*byte));
+            (cmp::min(len, usize::max_value() as u64) as usize, 10)
+        }
+        126 | 127 => return None,
+        len => (len as usize, 2),
+    };
+
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return None;
+        }
+
+        let mask = [buf[header_len], buf[header_len + 1], buf[header_len + 2], buf[header_len + 3]];
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    Some((opcode, mask, header_len, payload_len))
+}
+
+fn accept_key(key: &str) -> String {
+    let key = key.to_owned() + GUID;
+    base64::encode(&sha1(key.as_bytes()))
+}
+
+/// Sha1 is only used to verify the accept key of the handshake
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hash = [0; 20];
+    hash.copy_from_slice(digest::digest(&digest::SHA1, data).as_ref());
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accept_key, frame_header, handshake, host_header, sha1, WebSocketStream};
+    use codec::MqttCodec;
+    use futures::{Future, Sink, Stream};
+    use mqtt311::Packet;
+    use std::{
+        io::{self, Cursor, ErrorKind, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+    use tokio::{net::TcpStream, runtime::current_thread};
+    use tokio_codec::Decoder;
+
+    /// Accepts one websocket connection and echoes the payload of every frame
+    fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 1024];
+
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+
+            let request = String::from_utf8(buf).unwrap();
+            let key = request.lines()
+                             .find(|line| line.starts_with("Sec-WebSocket-Key:"))
+                             .map(|line| line["Sec-WebSocket-Key:".len()..].trim().to_owned())
+                             .unwrap();
+
+            let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                    Upgrade: websocket\r\n\
+                                    Connection: Upgrade\r\n\
+                                    Sec-WebSocket-Accept: {}\r\n\
+                                    Sec-WebSocket-Protocol: mqtt\r\n\r\n",
+                                   accept_key(&key));
+            stream.write_all(response.as_bytes()).unwrap();
+
+            let mut buf = Vec::new();
+            loop {
+                let n = stream.read(&mut chunk).unwrap();
+                if n == 0 {
+                    return;
+                }
+
+                buf.extend_from_slice(&chunk[..n]);
+                while let Some((_opcode, mask, header_len, payload_len)) = frame_header(&buf) {
+                    if buf.len() < header_len + payload_len {
+                        break;
+                    }
+
+                    let mask = mask.expect("Client frames should be masked");
+                    let payload: Vec<u8> = buf[header_len..header_len + payload_len].iter()
+                                                                                   .enumerate()
+                                                                                   .map(|(i, b)| b ^ mask[i % 4])
+                                                                                   .collect();
+                    buf.drain(..header_len + payload_len);
+
+                    // server frames aren't masked
+                    let mut frame = vec![0x82, payload.len() as u8];
+                    frame.extend(payload);
+                    stream.write_all(&frame).unwrap();
+                }
+            }
+        });
+
+        port
+    }
+
+    /// Serves the given server frames and records the frames written by the client
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn mock_stream(input: Vec<u8>) -> WebSocketStream<MockStream> {
+        let stream = MockStream { input: Cursor::new(input),
+                                  output: Vec::new() };
+        WebSocketStream::new(stream, &[], 1024)
+    }
+
+    #[test]
+    fn host_header_should_leave_out_default_ports() {
+        assert_eq!(host_header("example.com", 443), "example.com");
+        assert_eq!(host_header("example.com", 80), "example.com");
+        assert_eq!(host_header("example.com", 8083), "example.com:8083");
+    }
+
+    #[test]
+    fn sha1_and_accept_key_should_match_known_answers() {
+        let hash = sha1(b"abc");
+        let expected = [0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50, 0xc2, 0x6c,
+                        0x9c, 0xd0, 0xd8, 0x9d];
+        assert_eq!(hash, expected);
+
+        // example from rfc 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn mqtt_packets_should_be_framed_over_websocket() {
+        let port = echo_server();
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let future = TcpStream::connect(&addr).map_err(|e| e.into())
+                                              .and_then(|stream| handshake(stream, "localhost", "/mqtt", vec![], 1024))
+                                              .and_then(|stream| {
+                                                  let framed = MqttCodec::new(1024).framed(stream);
+                                                  framed.send(Packet::Pingreq).map_err(|e| e.into())
+                                              })
+                                              .and_then(|framed| framed.into_future().map_err(|(e, _)| e.into()));
+
+        let (packet, _framed) = rt.block_on(future).unwrap();
+        assert_eq!(packet, Some(Packet::Pingreq));
+    }
+
+    #[test]
+    fn frames_bigger_than_max_incoming_packet_size_should_throw_error() {
+        // 64 bit length of 1 TB without the payload
+        let mut stream = mock_stream(vec![0x82, 0x7F, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let mut buf = [0; 10];
+        match stream.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            v => panic!("Should throw invalid data error. Got = {:?}", v),
+        }
+
+        let mut stream = mock_stream(vec![0x82, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        match stream.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            v => panic!("Should throw invalid data error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn masked_frames_from_the_server_should_throw_error() {
+        let mut stream = mock_stream(vec![0x82, 0x82, 0x01, 0x02, 0x03, 0x04, 0xC1, 0x02]);
+        let mut buf = [0; 10];
+        match stream.read(&mut buf) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            v => panic!("Should throw invalid data error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn close_frame_should_be_answered_with_close_frame() {
+        // close with status 1000 followed by data which shouldn't be read anymore
+        let mut stream = mock_stream(vec![0x88, 0x02, 0x03, 0xE8, 0x82, 0x02, 0xD0, 0x00]);
+        let mut buf = [0; 10];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        let output = &stream.stream.output;
+        let (opcode, mask, header_len, payload_len) = frame_header(output).unwrap();
+        assert_eq!(opcode, 0x08);
+        assert_eq!(output.len(), header_len + payload_len);
+
+        let mask = mask.expect("Client frames should be masked");
+        let status: Vec<u8> = output[header_len..].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        assert_eq!(status, vec![0x03, 0xE8]);
+    }
+}
@@ -1,12 +1,60 @@
 use bytes::BytesMut;
-use mqtt311::{self, MqttRead, MqttWrite, Packet};
+use mqtt311::{self, MqttRead, MqttWrite, Packet, Publish, QoS};
 use std::{
     io::{self, Cursor, ErrorKind},
 };
 use tokio_codec::{Decoder, Encoder};
 
 #[derive(Debug)]
-pub struct MqttCodec;
+pub struct MqttCodec {
+    /// incoming packets bigger than this are rejected before they are buffered
+    max_incoming_packet_size: usize,
+}
+
+impl MqttCodec {
+    pub fn new(max_incoming_packet_size: usize) -> MqttCodec {
+        MqttCodec { max_incoming_packet_size }
+    }
+}
+
+/// Size of the publish packet on the wire (fixed header + variable header + payload)
+pub fn publish_packet_size(publish: &Publish) -> usize {
+    let pkid_len = match publish.qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce | QoS::ExactlyOnce => 2,
+    };
+
+    let remaining_len = 2 + publish.topic_name.len() + pkid_len + publish.payload.len();
+    let remaining_len_len = match remaining_len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+
+    1 + remaining_len_len + remaining_len
+}
+
+/// Reads the fixed header and returns the length of the complete packet.
+/// Returns `None` if the buffer doesn't contain the complete fixed header yet
+fn packet_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let mut remaining_len = 0;
+
+    // remaining length is encoded in (at most) 4 bytes after the first byte
+    for (i, byte) in buf.iter().skip(1).take(4).enumerate() {
+        remaining_len += (*byte as usize & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(1 + i + 1 + remaining_len));
+        }
+    }
+
+    if buf.len() >= 5 {
+        error!("Malformed remaining length");
+        return Err(io::Error::new(ErrorKind::InvalidData, "Malformed remaining length"));
+    }
+
+    Ok(None)
+}
 
 impl Decoder for MqttCodec {
     type Item = Packet;
@@ -23,6 +71,17 @@ impl Decoder for MqttCodec {
             return Ok(None);
         }
 
+        match packet_len(buf)? {
+            Some(len) if len > self.max_incoming_packet_size => {
+                error!("Incoming packet size {} crossed maximum {}", len, self.max_incoming_packet_size);
+                return Err(io::Error::new(ErrorKind::InvalidData, "Incoming packet size limit exceeded"));
+            }
+            // wait for the complete packet before trying to frame it
+            Some(len) if buf.len() < len => return Ok(None),
+            Some(_) => (),
+            None => return Ok(None),
+        }
+
         let (packet, len) = {
             let mut buf_ref = buf.as_ref();
             match buf_ref.read_packet_with_len() {
@@ -79,3 +138,69 @@ impl Encoder for MqttCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{publish_packet_size, MqttCodec};
+    use bytes::BytesMut;
+    use mqtt311::{Packet, PacketIdentifier, Publish, QoS};
+    use std::{io::ErrorKind, sync::Arc};
+    use tokio_codec::{Decoder, Encoder};
+
+    fn build_publish(qos: QoS, payload_len: usize) -> Publish {
+        let pkid = match qos {
+            QoS::AtMostOnce => None,
+            _ => Some(PacketIdentifier(1)),
+        };
+
+        Publish { dup: false,
+                  qos,
+                  retain: false,
+                  pkid,
+                  topic_name: "hello/world".to_owned(),
+                  payload: Arc::new(vec![1; payload_len]) }
+    }
+
+    #[test]
+    fn publish_packet_size_should_match_encoded_length() {
+        let mut codec = MqttCodec::new(1024 * 1024);
+
+        for &(qos, payload_len) in &[(QoS::AtMostOnce, 10), (QoS::AtLeastOnce, 200), (QoS::ExactlyOnce, 20_000)] {
+            let publish = build_publish(qos, payload_len);
+            let mut buf = BytesMut::new();
+
+            codec.encode(Packet::Publish(publish.clone()), &mut buf).unwrap();
+            assert_eq!(publish_packet_size(&publish), buf.len());
+        }
+    }
+
+    #[test]
+    fn decode_should_reject_packets_bigger_than_incoming_limit_before_buffering() {
+        let mut codec = MqttCodec::new(1024 * 1024);
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Publish(build_publish(QoS::AtLeastOnce, 2000)), &mut buf).unwrap();
+
+        // only the fixed header has arrived
+        let mut header = BytesMut::from(&buf[..4]);
+        let mut codec = MqttCodec::new(1024);
+
+        match codec.decode(&mut header) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => (),
+            v => panic!("Should throw size limit error. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn decode_should_wait_for_complete_packet() {
+        let mut codec = MqttCodec::new(1024 * 1024);
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Publish(build_publish(QoS::AtLeastOnce, 200)), &mut buf).unwrap();
+
+        let mut partial = BytesMut::from(&buf[..100]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+
+        let packet = codec.decode(&mut buf).unwrap();
+        assert_eq!(packet, Some(Packet::Publish(build_publish(QoS::AtLeastOnce, 200))));
+        assert_eq!(buf.len(), 0);
+    }
+}
@@ -3,6 +3,8 @@ use crossbeam_channel::RecvError;
 use futures::{sync::mpsc::SendError, Stream};
 #[cfg(feature = "jwt")]
 use jsonwebtoken;
+#[cfg(feature = "nativetls")]
+use native_tls;
 use mqtt311::Packet;
 use std::io::Error as IoError;
 use tokio_timer::{self, timeout};
@@ -16,12 +18,30 @@ pub enum ClientError {
     PacketSizeLimitExceeded,
     #[fail(display = "Client id should not be empty")]
     EmptyClientId,
+    #[fail(display = "Topic name should not be empty")]
+    EmptyTopic,
+    #[fail(display = "Topic name should not contain wildcards")]
+    WildcardInTopic,
+    #[fail(display = "Topic name should not contain null characters")]
+    NullCharacterInTopic,
+    #[fail(display = "Topic name is longer than 65535 bytes")]
+    TopicTooLong,
     #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
     MpscRequestSend(SendError<Request>),
     #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
     MpscCommandSend(SendError<Command>),
 }
 
+#[derive(Debug, Fail)]
+pub enum DeliveryError {
+    #[fail(display = "Session cleaned before the publish was acknowledged")]
+    SessionCleaned,
+    #[fail(display = "Publish dropped before it was acknowledged")]
+    Dropped,
+    #[fail(display = "Publish not acknowledged in time")]
+    Timeout,
+}
+
 #[derive(Debug, Fail, From)]
 pub enum MqttError {
     #[fail(display = "Connection failed")]
@@ -38,14 +58,27 @@ pub enum ConnectError {
     #[cfg(feature = "jwt")]
     #[fail(display = "Mqtt connection failed. Error = {}", _0)]
     Jwt(jsonwebtoken::errors::Error),
+    #[fail(display = "Token expiry is out of range. Expiry = {}", _0)]
+    InvalidTokenExpiry(i64),
+    #[fail(display = "Device key isn't valid base64")]
+    InvalidDeviceKey,
+    #[fail(display = "Aws sigv4 authentication needs a websocket connection method")]
+    AwsSigV4WithoutWebSocket,
     #[fail(display = "Io failed. Error = {}", _0)]
     Io(IoError),
+    #[cfg(feature = "nativetls")]
+    #[fail(display = "Tls failed. Error = {}", _0)]
+    Tls(native_tls::Error),
     #[fail(display = "Receiving connection status failed. Error = {}", _0)]
     Recv(RecvError),
+    #[fail(display = "Dns resolution failed. Error = {}", _0)]
+    Resolution(String),
     #[fail(display = "Empty dns list")]
     DnsListEmpty,
     #[fail(display = "Couldn't create mqtt connection in time")]
     Timeout,
+    #[fail(display = "Timer failed. Error = {}", _0)]
+    Timer(tokio_timer::Error),
     #[fail(display = "Unsolicited packet received while waiting for connack. Recived packet = {:?}",
            _0)]
     NotConnackPacket(Packet),
@@ -53,6 +86,30 @@ pub enum ConnectError {
     NoResponse,
     #[fail(display = "Builder doesn't contain certificate authority")]
     NoCertificateAuthority,
+    #[fail(display = "Invalid certificate")]
+    InvalidCertificate,
+    #[fail(display = "Invalid or unsupported private key")]
+    InvalidPrivateKey,
+    #[fail(display = "Client certificate and private key should be set together")]
+    IncompleteClientIdentity,
+    #[fail(display = "Tls server name is not a valid dns name")]
+    InvalidServerName,
+    #[fail(display = "Broker's certificate doesn't match any of the pins")]
+    PinMismatch,
+    #[fail(display = "Broker's certificate is rejected by the verifier")]
+    CertificateRejected,
+    #[fail(display = "Credentials provider failed to supply credentials")]
+    CredentialsUnavailable,
+    #[fail(display = "Websocket handshake failed. Error = {}", _0)]
+    WebSocketHandshake(&'static str),
+    #[fail(display = "Proxy requires authentication but no credentials are configured")]
+    ProxyAuthenticationRequired,
+    #[fail(display = "Proxy rejected the credentials")]
+    ProxyAuthenticationFailed,
+    #[fail(display = "Proxy couldn't connect to the broker")]
+    ProxyConnectFailed,
+    #[fail(display = "Invalid response from proxy")]
+    InvalidProxyResponse,
 }
 
 #[derive(Debug, Fail, From)]
@@ -69,6 +126,12 @@ pub enum NetworkError {
     PacketSizeLimitExceeded,
     #[fail(display = "Received unsolicited acknowledgment")]
     Unsolicited,
+    #[fail(display = "All packet identifiers are in use")]
+    PacketIdsExhausted,
+    #[fail(display = "Suback has {} return codes for {} subscribed topics", _0, _1)]
+    SubackMismatch(usize, usize),
+    #[fail(display = "Received unexpected packet = {:?}", _0)]
+    UnexpectedPacket(Packet),
     #[fail(display = "Tokio timer error = {}", _0)]
     Timer(tokio_timer::Error),
     #[fail(display = "Tokio timer error = {}", _0)]
@@ -77,6 +140,10 @@ pub enum NetworkError {
     UserReconnect,
     #[fail(display = "User requested for disconnect")]
     UserDisconnect,
+    #[fail(display = "Disconnecting from fallback endpoint to retry primary endpoint")]
+    ReturnToPrimary,
+    #[fail(display = "Disconnecting to reconnect with a fresh token before the current one expires")]
+    TokenExpiring,
     #[fail(display = "Network stream closed")]
     NetworkStreamClosed,
     #[fail(display = "Error to poll interleave")]
@@ -85,6 +152,16 @@ pub enum NetworkError {
     Blah,
 }
 
+/// Errors of `MqttEngine`. Connect errors happen while connecting and network errors
+/// after the connection is established
+#[derive(Debug, Fail, From)]
+pub enum EngineError {
+    #[fail(display = "Connect error = {}", _0)]
+    Connect(ConnectError),
+    #[fail(display = "Network error = {}", _0)]
+    Network(NetworkError),
+}
+
 #[derive(From)]
 pub enum PollError<S1, S2>
     where S1: Stream<Item = Packet, Error = NetworkError>,
@@ -1,6 +1,8 @@
+extern crate base64;
 extern crate bytes;
 #[cfg(feature = "jwt")]
 extern crate chrono;
+extern crate crc32fast;
 extern crate crossbeam_channel;
 extern crate futures;
 #[cfg(feature = "jwt")]
@@ -8,6 +10,7 @@ extern crate jsonwebtoken;
 extern crate mqtt311;
 #[cfg(feature = "nativetls")]
 extern crate native_tls;
+extern crate ring;
 extern crate tokio;
 extern crate tokio_codec;
 extern crate tokio_io;
@@ -17,7 +20,12 @@ extern crate tokio_timer;
 #[cfg(feature = "nativetls")]
 extern crate tokio_tls;
 #[cfg(feature = "rustls")]
+extern crate untrusted;
+#[cfg(feature = "rustls")]
 extern crate webpki;
+#[cfg(feature = "rustls")]
+extern crate webpki_roots;
+extern crate x509_parser;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -28,13 +36,26 @@ extern crate log;
 extern crate failure;
 extern crate core;
 extern crate pretty_env_logger;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+#[cfg(all(test, feature = "nativetls"))]
+extern crate openssl;
 
 pub mod client;
 pub mod codec;
 pub mod error;
 pub mod mqttoptions;
 
-pub use client::MqttClient;
-pub use mqtt311::{QoS, PacketIdentifier};
-pub use mqttoptions::{ConnectionMethod, MqttOptions, ReconnectOptions, SecurityOptions};
+pub use client::{
+    AsyncMqttClient, DeliveryToken, EngineOutput, MqttClient, MqttEngine, NotificationStream, PartialOutput, PublishBuilder,
+};
+pub use client::credentials::{CredentialsProvider, FileCredentials};
+pub use client::network::{CertificateVerifier, Resolver, SystemResolver};
+pub use client::session::{FileSessionStore, MemorySessionStore, Session, SessionStore, SessionUpdate};
+pub use mqtt311::{Packet, QoS, PacketIdentifier, SubscribeReturnCodes};
+pub use mqttoptions::{
+    AwsCredentials, AwsCustomAuthorizer, Backoff, ConnectionMethod, Endpoint, EndpointOrder, Jitter, MqttOptions,
+    NotificationOverflow, Pin, Proxy, ProxyType, ReconnectOptions, SecurityOptions, ServerVerification,
+};
 pub use crossbeam_channel::Receiver;
@@ -1,7 +1,23 @@
 use mqtt311::{Connect, LastWill, Protocol};
 
+use client::{
+    cloud,
+    credentials::CredentialsProvider,
+    network::{CertificateVerifier, Resolver, SystemResolver},
+    session::SessionStore,
+};
 use error::ConnectError;
-use std::time::Duration;
+#[cfg(feature = "jwt")]
+use jsonwebtoken::Algorithm;
+use std::{
+    cmp,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::path::PathBuf;
 
 /// Control how the connection is re-established if it is lost.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -17,6 +33,151 @@ pub enum ReconnectOptions {
     ///
     /// Before a reconnection attempt, sleep for the specified amount of seconds.
     Always(u64),
+    /// Always reconnect automatically.
+    ///
+    /// Before a reconnection attempt, sleep as per the backoff policy.
+    Backoff(Backoff),
+}
+
+/// Randomization of backoff delays so that a fleet of clients doesn't
+/// reconnect at the same time after a broker restart
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Jitter {
+    /// Sleep exactly for the exponential delay
+    None,
+    /// Sleep for a random duration between 0 and the exponential delay
+    Full,
+    /// Sleep for a random duration between the initial delay and `multiplier`
+    /// times the previous delay
+    Decorrelated,
+}
+
+/// Exponentially increasing delays between reconnection attempts
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: u32,
+    jitter: Jitter,
+    max_attempts: Option<u32>,
+    stable_after: Duration,
+}
+
+impl Backoff {
+    /// Delays start with `initial_delay` and double after every failed attempt till
+    /// `max_delay`. Full jitter is applied by default
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Backoff {
+        Backoff { initial_delay,
+                  max_delay,
+                  multiplier: 2,
+                  jitter: Jitter::Full,
+                  max_attempts: None,
+                  stable_after: Duration::from_secs(60) }
+    }
+
+    /// Set the factor by which the delay grows after every failed attempt
+    pub fn set_multiplier(mut self, multiplier: u32) -> Self {
+        if multiplier < 1 {
+            panic!("Backoff multiplier should be >= 1");
+        }
+
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn set_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Give up after these many consecutive failed attempts. Event loop stops and
+    /// the user is notified with `Notification::ReconnectAttemptsExhausted`
+    pub fn set_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    pub fn max_attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    /// Connections which stay up for this long reset the delay back to the initial delay
+    pub fn set_stable_after(mut self, stable_after: Duration) -> Self {
+        self.stable_after = stable_after;
+        self
+    }
+
+    pub fn stable_after(&self) -> Duration {
+        self.stable_after
+    }
+
+    /// Delay before the given attempt (0 being the first reconnection attempt).
+    /// `previous` is the delay before the last attempt and is only used by
+    /// decorrelated jitter
+    pub fn delay(&self, attempt: u32, previous: Duration) -> Duration {
+        match self.jitter {
+            Jitter::None => self.exponential_delay(attempt),
+            Jitter::Full => random_between(Duration::from_secs(0), self.exponential_delay(attempt)),
+            Jitter::Decorrelated => {
+                let previous = cmp::max(previous, self.initial_delay);
+                let high = previous.checked_mul(self.multiplier).unwrap_or(self.max_delay);
+                let high = cmp::min(high, self.max_delay);
+                random_between(cmp::min(self.initial_delay, high), high)
+            }
+        }
+    }
+
+    fn exponential_delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 0..attempt {
+            delay = match delay.checked_mul(self.multiplier) {
+                Some(delay) if delay < self.max_delay => delay,
+                _ => return self.max_delay,
+            };
+        }
+
+        cmp::min(delay, self.max_delay)
+    }
+}
+
+/// Shuffles the endpoints with Fisher-Yates
+pub(crate) fn shuffle_endpoints(endpoints: &mut Vec<Endpoint>) {
+    for i in (1..endpoints.len()).rev() {
+        let j = random_u64() as usize % (i + 1);
+        endpoints.swap(i, j);
+    }
+}
+
+// Randomness comes from std's randomly seeded hasher to avoid depending on a rng crate
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Random duration in [low, high]
+fn random_between(low: Duration, high: Duration) -> Duration {
+    let to_nanos = |d: Duration| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos());
+    let (low, high) = (to_nanos(low), to_nanos(high));
+    if high <= low {
+        return Duration::from_nanos(low);
+    }
+
+    Duration::from_nanos(low + random_u64() % (high - low + 1))
+}
+
+/// Control what happens when the user doesn't consume notifications as fast
+/// as they are coming in and the notification channel is full.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NotificationOverflow {
+    /// Block the event loop till there is space in the channel. Network isn't read
+    /// in the meantime which slows down the broker. Pings are delayed too, so a very
+    /// slow consumer might see disconnections
+    Block,
+    /// Throw away the oldest notification in the channel to make space
+    DropOldest,
+    /// Throw away the incoming notification
+    DropNewest,
+    /// Don't limit the channel. Capacity is ignored
+    Unbounded,
 }
 
 /// Client authentication option for mqtt connect packet
@@ -28,15 +189,279 @@ pub enum SecurityOptions {
     UsernamePassword((String, String)),
     #[cfg(feature = "jwt")]
     /// Authenticate against a Google Cloud IoT Core project with the triple
-    /// `(project name, private_key.der to sign jwt, expiry in seconds)`.
+    /// `(project name, rsa private_key.der to sign jwt, expiry in minutes)`.
+    /// Expiry should be between 1 minute and 24 hours
     GcloudIot((String, Vec<u8>, i64)),
+    #[cfg(feature = "jwt")]
+    /// Same as `GcloudIot` but signs the jwt with a pkcs8 der encoded P-256 key (ES256)
+    GcloudIotEs256((String, Vec<u8>, i64)),
+    /// Authenticate against azure iot hub with a sas token generated from the device key.
+    /// `(hub hostname, device id, base64 encoded device key, expiry in minutes)`.
+    /// Client id is replaced by the device id. The client reconnects with a fresh
+    /// token before the token expires
+    AzureIotHub((String, String, String, i64)),
+    /// Authenticate against aws iot with a custom authorizer. Connecting on port 443
+    /// needs the `mqtt` alpn protocol
+    AwsCustomAuthorizer(AwsCustomAuthorizer),
+    /// Authenticate against aws iot by signing the websocket url (sigv4). Connecting
+    /// with other connection methods fails, as they can't carry the signature
+    AwsSigV4(AwsCredentials),
 }
 
 #[derive(Clone, Debug)]
 pub enum ConnectionMethod {
     Tcp,
-    // ca and, optionally, a pair of client cert and client key
+    // ca and, optionally, a pair of client cert and client key. Pem or der encoded.
+    // Ca can only be empty when the system's root certificates are trusted or the
+    // server verification doesn't use a ca (e.g pins)
     Tls(Vec<u8>, Option<(Vec<u8>, Vec<u8>)>),
+    // path of the websocket endpoint and extra headers of the http upgrade request
+    WebSocket(String, Vec<(String, String)>),
+    // tls options of `Tls` followed by websocket options of `WebSocket`
+    SecureWebSocket(Vec<u8>, Option<(Vec<u8>, Vec<u8>)>, String, Vec<(String, String)>),
+    // ca and a pkcs12 archive with client cert and key followed by its password
+    #[cfg(feature = "nativetls")]
+    TlsPkcs12(Vec<u8>, Vec<u8>, String),
+    // path of the broker's socket. Broker address is ignored
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A broker in a cluster of brokers
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+    /// overrides the connection method in `MqttOptions`
+    connection_method: Option<ConnectionMethod>,
+}
+
+impl Endpoint {
+    pub fn new<S: Into<String>>(host: S, port: u16) -> Endpoint {
+        Endpoint { host: host.into(),
+                   port,
+                   connection_method: None }
+    }
+
+    /// Set how to connect to this broker. Connection method of `MqttOptions`
+    /// is used by default
+    pub fn set_connection_method(mut self, opts: ConnectionMethod) -> Self {
+        self.connection_method = Some(opts);
+        self
+    }
+
+    pub fn connection_method(&self) -> Option<ConnectionMethod> {
+        self.connection_method.clone()
+    }
+
+    pub fn address(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+}
+
+/// Sha256 hash of the broker's certificate or of its der encoded public key
+/// (`SubjectPublicKeyInfo`)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Pin {
+    Certificate([u8; 32]),
+    PublicKey([u8; 32]),
+}
+
+/// Control how the broker's tls certificate is verified
+#[derive(Clone, Debug)]
+pub enum ServerVerification {
+    /// Verify the certificate chain against the certificate authority and the server name
+    CertificateAuthority,
+    /// Verify like `CertificateAuthority` and require a pin to match the broker's certificate
+    /// or an issuer on the verified path to the certificate authority. Native tls only
+    /// checks the broker's certificate
+    CertificateAuthorityAndPins(Vec<Pin>),
+    /// Only require a pin to match the broker's certificate (the leaf of the chain).
+    /// Certificate authority and server name aren't verified, so the certificate
+    /// authority of tls connection methods can be empty
+    Pins(Vec<Pin>),
+    /// Leave the verification to the user
+    Custom(Arc<CertificateVerifier>),
+    /// Accept any certificate. Only for development against brokers with self signed
+    /// certificates. Never use this in production
+    DangerousAcceptInvalidCertificates,
+}
+
+impl ServerVerification {
+    /// `false` for verifications which don't look at the certificate authority. Tls
+    /// works without one then
+    pub fn uses_certificate_authority(&self) -> bool {
+        match *self {
+            ServerVerification::CertificateAuthority | ServerVerification::CertificateAuthorityAndPins(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Protocol spoken by the proxy
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProxyType {
+    /// Tunnel with the `CONNECT` method of HTTP/1.1
+    Http,
+    Socks5,
+}
+
+/// Proxy through which the tcp connection to the broker is tunneled
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    proxy_type: ProxyType,
+    host: String,
+    port: u16,
+    /// username and password
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn new<S: Into<String>>(proxy_type: ProxyType, host: S, port: u16) -> Proxy {
+        Proxy { proxy_type,
+                host: host.into(),
+                port,
+                credentials: None }
+    }
+
+    /// Authenticate with the proxy using basic auth for http proxies and
+    /// username/password auth for socks5 proxies
+    pub fn set_credentials<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn proxy_type(&self) -> ProxyType {
+        self.proxy_type
+    }
+
+    pub fn address(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+
+    pub fn credentials(&self) -> Option<(String, String)> {
+        self.credentials.clone()
+    }
+}
+
+/// Aws credentials which sign the websocket url of aws iot (sigv4)
+#[derive(Clone, Debug)]
+pub struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    region: String,
+}
+
+impl AwsCredentials {
+    pub fn new<A, S, R>(access_key_id: A, secret_access_key: S, region: R) -> AwsCredentials
+        where A: Into<String>,
+              S: Into<String>,
+              R: Into<String>
+    {
+        AwsCredentials { access_key_id: access_key_id.into(),
+                         secret_access_key: secret_access_key.into(),
+                         session_token: None,
+                         region: region.into() }
+    }
+
+    /// Session token of temporary credentials
+    pub fn set_session_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.session_token = Some(token.into());
+        self
+    }
+
+    pub fn access_key_id(&self) -> String {
+        self.access_key_id.clone()
+    }
+
+    pub fn secret_access_key(&self) -> String {
+        self.secret_access_key.clone()
+    }
+
+    pub fn session_token(&self) -> Option<String> {
+        self.session_token.clone()
+    }
+
+    pub fn region(&self) -> String {
+        self.region.clone()
+    }
+}
+
+/// Fields of the username which aws iot passes to a custom authorizer
+#[derive(Clone, Debug)]
+pub struct AwsCustomAuthorizer {
+    authorizer_name: String,
+    username: Option<String>,
+    /// token key name and token
+    token: Option<(String, String)>,
+    token_signature: Option<String>,
+    password: Option<String>,
+}
+
+impl AwsCustomAuthorizer {
+    pub fn new<S: Into<String>>(authorizer_name: S) -> AwsCustomAuthorizer {
+        AwsCustomAuthorizer { authorizer_name: authorizer_name.into(),
+                              username: None,
+                              token: None,
+                              token_signature: None,
+                              password: None }
+    }
+
+    /// Username passed to the authorizer in front of the authorizer fields
+    pub fn set_username<S: Into<String>>(mut self, username: S) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Token and the name of the field it is sent in (token key name of the authorizer)
+    pub fn set_token<K: Into<String>, T: Into<String>>(mut self, key_name: K, token: T) -> Self {
+        self.token = Some((key_name.into(), token.into()));
+        self
+    }
+
+    /// Base64 encoded signature of the token. Only needed for authorizers
+    /// with token signing enabled
+    pub fn set_token_signature<S: Into<String>>(mut self, signature: S) -> Self {
+        self.token_signature = Some(signature.into());
+        self
+    }
+
+    /// Password of the connect packet
+    pub fn set_password<S: Into<String>>(mut self, password: S) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    pub fn authorizer_name(&self) -> String {
+        self.authorizer_name.clone()
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn token(&self) -> Option<(String, String)> {
+        self.token.clone()
+    }
+
+    pub fn token_signature(&self) -> Option<String> {
+        self.token_signature.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
+/// Order in which endpoints are tried
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndpointOrder {
+    /// Try endpoints in the given order
+    Ordered,
+    /// Shuffle the endpoints once when the client starts. Spreads a fleet of
+    /// clients across the cluster
+    Shuffled,
 }
 
 
@@ -53,14 +478,44 @@ pub struct MqttOptions {
     client_id: String,
     /// connection method
     connection_method: ConnectionMethod,
+    /// brokers to fail over to. First one is the primary
+    endpoints: Vec<Endpoint>,
+    /// order in which endpoints are tried
+    endpoint_order: EndpointOrder,
+    /// time after which client goes back to the primary endpoint after a failover
+    primary_retry_interval: Option<Duration>,
+    /// resolves broker host names
+    resolver: Arc<Resolver>,
+    /// proxy to tunnel the broker connection through
+    proxy: Option<Proxy>,
+    /// tls server name when it's different from the broker address
+    tls_server_name: Option<String>,
+    /// protocols offered with alpn during the tls handshake
+    alpn_protocols: Vec<String>,
+    /// trust the system's root certificates along with the ca of the connection method
+    system_roots: bool,
+    /// verification of the broker's certificate
+    server_verification: ServerVerification,
     /// reconnection options
     reconnect: ReconnectOptions,
     /// security options
     security: SecurityOptions,
-    /// maximum packet size
+    /// credentials consulted on every connection attempt
+    credentials_provider: Option<Arc<CredentialsProvider>>,
+    /// maximum size of outgoing packets
     max_packet_size: usize,
+    /// maximum size of incoming packets
+    max_incoming_packet_size: usize,
+    /// maximum number of unacknowledged qos1 & qos2 publishes
+    max_inflight: usize,
+    /// persists the qos state of sessions which aren't clean
+    session_store: Option<Arc<SessionStore>>,
     /// last will and testament
     last_will: Option<LastWill>,
+    /// capacity of the notification channel
+    notification_channel_capacity: usize,
+    /// what to do when the notification channel is full
+    notification_overflow: NotificationOverflow,
 }
 
 impl Default for MqttOptions {
@@ -71,10 +526,25 @@ impl Default for MqttOptions {
                       clean_session: true,
                       client_id: "test-client".into(),
                       connection_method: ConnectionMethod::Tcp,
+                      endpoints: Vec::new(),
+                      endpoint_order: EndpointOrder::Ordered,
+                      primary_retry_interval: Some(Duration::from_secs(300)),
+                      resolver: Arc::new(SystemResolver),
+                      proxy: None,
+                      tls_server_name: None,
+                      alpn_protocols: Vec::new(),
+                      system_roots: false,
+                      server_verification: ServerVerification::CertificateAuthority,
                       reconnect: ReconnectOptions::AfterFirstSuccess(10),
                       security: SecurityOptions::None,
+                      credentials_provider: None,
                       max_packet_size: 256 * 1024,
-                      last_will: None }
+                      max_incoming_packet_size: 256 * 1024,
+                      max_inflight: 100,
+                      session_store: None,
+                      last_will: None,
+                      notification_channel_capacity: 10,
+                      notification_overflow: NotificationOverflow::DropNewest }
     }
 }
 
@@ -92,16 +562,162 @@ impl MqttOptions {
                       clean_session: true,
                       client_id: id,
                       connection_method: ConnectionMethod::Tcp,
+                      endpoints: Vec::new(),
+                      endpoint_order: EndpointOrder::Ordered,
+                      primary_retry_interval: Some(Duration::from_secs(300)),
+                      resolver: Arc::new(SystemResolver),
+                      proxy: None,
+                      tls_server_name: None,
+                      alpn_protocols: Vec::new(),
+                      system_roots: false,
+                      server_verification: ServerVerification::CertificateAuthority,
                       reconnect: ReconnectOptions::AfterFirstSuccess(10),
                       security: SecurityOptions::None,
+                      credentials_provider: None,
                       max_packet_size: 256 * 1024,
-                      last_will: None }
+                      max_incoming_packet_size: 256 * 1024,
+                      max_inflight: 100,
+                      session_store: None,
+                      last_will: None,
+                      notification_channel_capacity: 10,
+                      notification_overflow: NotificationOverflow::DropNewest }
     }
 
     pub fn broker_address(&self) -> (String, u16) {
         (self.broker_addr.clone(), self.port)
     }
 
+    /// Set a list of brokers to connect to. Client moves to the next endpoint when
+    /// connection to the current one fails. First endpoint is the primary and
+    /// replaces the broker address given in `new`
+    pub fn set_endpoints(mut self, endpoints: Vec<Endpoint>) -> Self {
+        if endpoints.is_empty() {
+            panic!("Endpoint list should not be empty");
+        }
+
+        let (host, port) = endpoints[0].address();
+        self.broker_addr = host;
+        self.port = port;
+        self.endpoints = endpoints;
+        self
+    }
+
+    /// All the endpoints with their connection methods resolved
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        let endpoints = if self.endpoints.is_empty() {
+            vec![Endpoint::new(self.broker_addr.clone(), self.port)]
+        } else {
+            self.endpoints.clone()
+        };
+
+        endpoints.into_iter()
+                 .map(|mut endpoint| {
+                     if endpoint.connection_method.is_none() {
+                         endpoint.connection_method = Some(self.connection_method.clone());
+                     }
+                     endpoint
+                 })
+                 .collect()
+    }
+
+    pub fn set_endpoint_order(mut self, order: EndpointOrder) -> Self {
+        self.endpoint_order = order;
+        self
+    }
+
+    pub fn endpoint_order(&self) -> EndpointOrder {
+        self.endpoint_order
+    }
+
+    /// Set the resolver used to look up broker addresses. Uses the operating
+    /// system's resolver by default
+    pub fn set_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn resolver(&self) -> Arc<Resolver> {
+        self.resolver.clone()
+    }
+
+    /// Connect to brokers through a proxy. Doesn't apply to unix sockets
+    pub fn set_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn proxy(&self) -> Option<Proxy> {
+        self.proxy.clone()
+    }
+
+    /// Set the name used for sni and to verify the broker's certificate. Useful
+    /// when connecting to an ip address or through a load balancer
+    pub fn set_tls_server_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.tls_server_name = Some(name.into());
+        self
+    }
+
+    pub fn tls_server_name(&self) -> Option<String> {
+        self.tls_server_name.clone()
+    }
+
+    /// Set the protocols offered with alpn. e.g `x-amzn-mqtt-ca` for mqtt on
+    /// port 443 of aws iot
+    pub fn set_alpn_protocols(mut self, protocols: Vec<String>) -> Self {
+        self.alpn_protocols = protocols;
+        self
+    }
+
+    pub fn alpn_protocols(&self) -> Vec<String> {
+        self.alpn_protocols.clone()
+    }
+
+    /// Trust the system's root certificates. The ca of tls connection methods can
+    /// then be empty. Connecting with an empty ca fails otherwise, unless the server
+    /// verification doesn't use a ca
+    pub fn set_use_system_roots(mut self, system_roots: bool) -> Self {
+        self.system_roots = system_roots;
+        self
+    }
+
+    pub fn use_system_roots(&self) -> bool {
+        self.system_roots
+    }
+
+    /// Set a provider which is asked for tls identity, certificate authority and
+    /// username/password on every connection attempt. Supplied credentials take
+    /// precedence over the connection method and security options
+    pub fn set_credentials_provider(mut self, provider: Arc<CredentialsProvider>) -> Self {
+        self.credentials_provider = Some(provider);
+        self
+    }
+
+    pub fn credentials_provider(&self) -> Option<Arc<CredentialsProvider>> {
+        self.credentials_provider.clone()
+    }
+
+    /// Set how the broker's certificate is verified. Verifies against the
+    /// certificate authority by default
+    pub fn set_server_verification(mut self, verification: ServerVerification) -> Self {
+        self.server_verification = verification;
+        self
+    }
+
+    pub fn server_verification(&self) -> ServerVerification {
+        self.server_verification.clone()
+    }
+
+    /// Set time after which client disconnects from a fallback endpoint and tries
+    /// the primary endpoint again. `None` stays with the fallback till it fails
+    pub fn set_primary_retry_interval(mut self, interval: Option<Duration>) -> Self {
+        self.primary_retry_interval = interval;
+        self
+    }
+
+    pub fn primary_retry_interval(&self) -> Option<Duration> {
+        self.primary_retry_interval
+    }
+
     /// Set number of seconds after which client should ping the broker
     /// if there is no other data exchange
     pub fn set_keep_alive(mut self, secs: u16) -> Self {
@@ -117,7 +733,8 @@ impl MqttOptions {
         self.keep_alive
     }
 
-    /// Set packet size limit (in Kilo Bytes)
+    /// Set outgoing packet size limit (in Kilo Bytes). Limit applies to the
+    /// size of the whole packet and not just the payload
     pub fn set_max_packet_size(mut self, sz: usize) -> Self {
         self.max_packet_size = sz * 1024;
         self
@@ -127,6 +744,46 @@ impl MqttOptions {
         self.max_packet_size
     }
 
+    /// Set incoming packet size limit (in Kilo Bytes). Connection is closed
+    /// when the broker sends a bigger packet
+    pub fn set_max_incoming_packet_size(mut self, sz: usize) -> Self {
+        self.max_incoming_packet_size = sz * 1024;
+        self
+    }
+
+    pub fn max_incoming_packet_size(&self) -> usize {
+        self.max_incoming_packet_size
+    }
+
+    /// Set the maximum number of qos1 & qos2 publishes waiting for acknowledgment.
+    /// Once the limit is reached, the event loop holds back new publishes (of every qos,
+    /// to keep their order) till acks arrive. Subscribes, unsubscribes and reconnects
+    /// aren't held back
+    pub fn set_max_inflight(mut self, max_inflight: u16) -> Self {
+        if max_inflight == 0 {
+            panic!("Max inflight should be > 0");
+        }
+
+        self.max_inflight = usize::from(max_inflight);
+        self
+    }
+
+    pub fn max_inflight(&self) -> usize {
+        self.max_inflight
+    }
+
+    /// Set a store which persists unacknowledged messages of the session. With
+    /// `clean_session(false)`, they are loaded again when the client starts and
+    /// retransmitted after the connection
+    pub fn set_session_store(mut self, store: Arc<SessionStore>) -> Self {
+        self.session_store = Some(store);
+        self
+    }
+
+    pub fn session_store(&self) -> Option<Arc<SessionStore>> {
+        self.session_store.clone()
+    }
+
     /// `clean_session = true` removes all the state from queues & instructs the broker
     /// to clean all the client state when client disconnects.
     ///
@@ -172,6 +829,10 @@ impl MqttOptions {
         self
     }
 
+    pub fn security_opts(&self) -> SecurityOptions {
+        self.security.clone()
+    }
+
     /// Set last will and testament
     pub fn set_last_will(mut self, last_will: LastWill) -> Self {
         self.last_will = Some(last_will);
@@ -184,21 +845,105 @@ impl MqttOptions {
         self
     }
 
+    /// Set the capacity of the notification channel
+    pub fn set_notification_channel_capacity(mut self, capacity: usize) -> Self {
+        self.notification_channel_capacity = capacity;
+        self
+    }
+
+    pub fn notification_channel_capacity(&self) -> usize {
+        self.notification_channel_capacity
+    }
+
+    /// Set what to do when the user isn't consuming notifications fast enough.
+    /// Dropped notifications (including acked publishes) are reported with
+    /// `Notification::Dropped`
+    pub fn set_notification_overflow(mut self, overflow: NotificationOverflow) -> Self {
+        self.notification_overflow = overflow;
+        self
+    }
+
+    pub fn notification_overflow(&self) -> NotificationOverflow {
+        self.notification_overflow
+    }
+
+    /// Lifetime of the token in the connect packet. Client reconnects with a
+    /// fresh token before it expires. `None` for credentials which don't expire
+    /// and for expiries which are out of range
+    pub fn token_lifetime(&self) -> Option<Duration> {
+        match self.security {
+            #[cfg(feature = "jwt")]
+            SecurityOptions::GcloudIot((_, _, expiry)) | SecurityOptions::GcloudIotEs256((_, _, expiry)) => {
+                expiry_lifetime(expiry, MAX_JWT_EXPIRY)
+            }
+            SecurityOptions::AzureIotHub((_, _, _, expiry)) => expiry_lifetime(expiry, MAX_SAS_EXPIRY),
+            _ => None,
+        }
+    }
+
     pub fn connect_packet(&self) -> Result<Connect, ConnectError> {
+        let mut client_id = self.client_id.clone();
         let (username, password) = match self.security.clone() {
             SecurityOptions::UsernamePassword((username, password)) => (Some(username), Some(password)),
             #[cfg(feature = "jwt")]
             SecurityOptions::GcloudIot((projectname, key, expiry)) => {
                 let username = Some("unused".to_owned());
-                let password = Some(gen_iotcore_password(projectname, &key, expiry)?);
+                let password = Some(gen_iotcore_password(projectname, &key, expiry, Algorithm::RS256)?);
+                (username, password)
+            }
+            #[cfg(feature = "jwt")]
+            SecurityOptions::GcloudIotEs256((projectname, key, expiry)) => {
+                let username = Some("unused".to_owned());
+                let password = Some(gen_iotcore_password(projectname, &key, expiry, Algorithm::ES256)?);
                 (username, password)
             }
+            SecurityOptions::AzureIotHub((hub, device_id, key, expiry)) => {
+                let expires_at = expiry_lifetime(expiry, MAX_SAS_EXPIRY).and_then(|lifetime| {
+                                                                            cloud::unix_time().checked_add(lifetime.as_secs())
+                                                                        });
+                let expires_at = expires_at.ok_or(ConnectError::InvalidTokenExpiry(expiry))?;
+                let key = cloud::base64_decode(&key).ok_or(ConnectError::InvalidDeviceKey)?;
+                let password = cloud::azure_sas_token(&hub, &device_id, &key, expires_at);
+                let username = cloud::azure_username(&hub, &device_id);
+                client_id = device_id;
+                (Some(username), Some(password))
+            }
+            SecurityOptions::AwsCustomAuthorizer(authorizer) => {
+                (Some(cloud::aws_custom_authorizer_username(&authorizer)), authorizer.password())
+            }
+            SecurityOptions::AwsSigV4(_) => {
+                // signature goes in the websocket upgrade request. other transports would
+                // connect without any credentials
+                let websocket = |endpoint: &Endpoint| match endpoint.connection_method {
+                    Some(ConnectionMethod::WebSocket(..)) | Some(ConnectionMethod::SecureWebSocket(..)) => true,
+                    _ => false,
+                };
+
+                if !self.endpoints().iter().all(websocket) {
+                    return Err(ConnectError::AwsSigV4WithoutWebSocket);
+                }
+
+                (None, None)
+            }
             SecurityOptions::None => (None, None),
         };
 
+        let provided = match self.credentials_provider {
+            Some(ref provider) => provider.username_password().map_err(|e| {
+                                                                  error!("Failed to get username and password. Error = {:?}", e);
+                                                                  ConnectError::CredentialsUnavailable
+                                                              })?,
+            None => None,
+        };
+
+        let (username, password) = match provided {
+            Some((username, password)) => (Some(username), Some(password)),
+            None => (username, password),
+        };
+
         let connect = Connect { protocol: Protocol::MQTT(4),
                                 keep_alive: self.keep_alive.as_secs() as u16,
-                                client_id: self.client_id.clone(),
+                                client_id,
                                 clean_session: self.clean_session,
                                 last_will: self.last_will.clone(),
                                 username,
@@ -215,18 +960,40 @@ struct Claims {
     aud: String,
 }
 
+/// Maximum lifetime (in minutes) of azure iot hub sas tokens
+const MAX_SAS_EXPIRY: i64 = 365 * 24 * 60;
+
+/// Lifetime of a token which expires in `expiry` minutes. `None` if the expiry isn't
+/// in `1..=max`
+fn expiry_lifetime(expiry: i64, max: i64) -> Option<Duration> {
+    if expiry < 1 || expiry > max {
+        return None;
+    }
+
+    Some(Duration::from_secs(expiry as u64 * 60))
+}
+
+/// Maximum lifetime (in minutes) of iot core jwts
+#[cfg(feature = "jwt")]
+const MAX_JWT_EXPIRY: i64 = 24 * 60;
+
 #[cfg(feature = "jwt")]
 // Generates a new password for mqtt client authentication
-pub fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<String, ConnectError> {
+pub fn gen_iotcore_password(project: String, key: &[u8], expiry: i64, algorithm: Algorithm) -> Result<String, ConnectError> {
     use chrono::{self, Utc};
-    use jsonwebtoken::{encode, Algorithm, Header};
+    use jsonwebtoken::{encode, Header};
+
+    if expiry < 1 || expiry > MAX_JWT_EXPIRY {
+        return Err(ConnectError::InvalidTokenExpiry(expiry));
+    }
 
     let time = Utc::now();
-    let jwt_header = Header::new(Algorithm::RS256);
+    let jwt_header = Header::new(algorithm);
     let iat = time.timestamp();
-    let exp = time.checked_add_signed(chrono::Duration::minutes(expiry))
-                  .expect("Unable to create expiry")
-                  .timestamp();
+    let exp = match time.checked_add_signed(chrono::Duration::minutes(expiry)) {
+        Some(exp) => exp.timestamp(),
+        None => return Err(ConnectError::InvalidTokenExpiry(expiry)),
+    };
 
     let claims = Claims { iat, exp, aud: project };
 
@@ -235,7 +1002,30 @@ pub fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<
 
 #[cfg(test)]
 mod test {
-    use mqttoptions::{MqttOptions, ReconnectOptions};
+    use client::credentials::CredentialsProvider;
+    use error::ConnectError;
+    use mqttoptions::{Backoff, ConnectionMethod, Endpoint, Jitter, MqttOptions, ReconnectOptions, SecurityOptions};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Hands out a new token on every connection attempt. Fails after 2 tokens
+    #[derive(Debug, Default)]
+    struct RotatingToken(AtomicUsize);
+
+    impl CredentialsProvider for RotatingToken {
+        fn username_password(&self) -> io::Result<Option<(String, String)>> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                n if n < 2 => Ok(Some(("device".to_owned(), format!("token-{}", n)))),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "token file missing")),
+            }
+        }
+    }
 
     #[test]
     #[should_panic]
@@ -250,4 +1040,163 @@ mod test {
         let _mqtt_opts = MqttOptions::new("", "127.0.0.1", 1883).set_reconnect_opts(ReconnectOptions::Always(10))
                                                                 .set_clean_session(true);
     }
+
+    #[test]
+    fn backoff_delay_should_grow_exponentially_till_max_delay() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30)).set_jitter(Jitter::None);
+
+        assert_eq!(backoff.delay(0, Duration::from_secs(0)), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1, Duration::from_secs(0)), Duration::from_secs(2));
+        assert_eq!(backoff.delay(4, Duration::from_secs(0)), Duration::from_secs(16));
+        assert_eq!(backoff.delay(5, Duration::from_secs(0)), Duration::from_secs(30));
+        assert_eq!(backoff.delay(1000, Duration::from_secs(0)), Duration::from_secs(30));
+
+        let backoff = backoff.set_multiplier(3);
+        assert_eq!(backoff.delay(2, Duration::from_secs(0)), Duration::from_secs(9));
+    }
+
+    #[test]
+    fn backoff_delay_with_jitter_should_be_within_bounds() {
+        let full = Backoff::new(Duration::from_secs(1), Duration::from_secs(30)).set_jitter(Jitter::Full);
+        let decorrelated = full.set_jitter(Jitter::Decorrelated);
+
+        let mut previous = Duration::from_secs(0);
+        for attempt in 0..100 {
+            let delay = full.delay(attempt, previous);
+            assert!(delay <= Duration::from_secs(30));
+            if attempt < 4 {
+                assert!(delay <= Duration::from_secs(1 << attempt));
+            }
+
+            let delay = decorrelated.delay(attempt, previous);
+            assert!(delay >= Duration::from_secs(1));
+            assert!(delay <= Duration::from_secs(30));
+            assert!(delay <= previous * 2 || delay <= Duration::from_secs(2));
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn endpoints_should_inherit_connection_method_and_set_primary() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883);
+        let endpoints = opts.endpoints();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].address(), ("127.0.0.1".to_owned(), 1883));
+
+        let tls = ConnectionMethod::Tls(vec![1, 2, 3], None);
+        let opts = opts.set_endpoints(vec![Endpoint::new("broker1", 1883),
+                                           Endpoint::new("broker2", 8883).set_connection_method(tls)]);
+
+        assert_eq!(opts.broker_address(), ("broker1".to_owned(), 1883));
+
+        let endpoints = opts.endpoints();
+        match endpoints[0].connection_method() {
+            Some(ConnectionMethod::Tcp) => (),
+            v => panic!("Should inherit tcp connection method. Got = {:?}", v),
+        }
+
+        match endpoints[1].connection_method() {
+            Some(ConnectionMethod::Tls(ca, None)) => assert_eq!(ca, vec![1, 2, 3]),
+            v => panic!("Should use endpoint's connection method. Got = {:?}", v),
+        }
+    }
+
+    #[test]
+    fn credentials_provider_should_be_consulted_for_every_connect_packet() {
+        let security = SecurityOptions::UsernamePassword(("static".to_owned(), "password".to_owned()));
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security)
+                                                                 .set_credentials_provider(Arc::new(RotatingToken::default()));
+
+        let connect = opts.connect_packet().unwrap();
+        assert_eq!(connect.username, Some("device".to_owned()));
+        assert_eq!(connect.password, Some("token-0".to_owned()));
+
+        let connect = opts.connect_packet().unwrap();
+        assert_eq!(connect.password, Some("token-1".to_owned()));
+
+        match opts.connect_packet() {
+            Err(ConnectError::CredentialsUnavailable) => (),
+            v => panic!("Should throw credentials error. Got = {:?}", v),
+        }
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn out_of_range_jwt_expiry_should_return_error() {
+        use jsonwebtoken::Algorithm;
+        use mqttoptions::gen_iotcore_password;
+
+        for expiry in &[0, -1, 24 * 60 + 1, i64::max_value()] {
+            match gen_iotcore_password("project".to_owned(), &[1, 2, 3], *expiry, Algorithm::ES256) {
+                Err(ConnectError::InvalidTokenExpiry(e)) => assert_eq!(e, *expiry),
+                v => panic!("Should throw invalid expiry error. Got = {:?}", v),
+            }
+        }
+
+        let security = SecurityOptions::GcloudIotEs256(("project".to_owned(), vec![1, 2, 3], 60));
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        assert_eq!(opts.token_lifetime(), Some(Duration::from_secs(3600)));
+
+        for expiry in &[0, -1, i64::min_value(), i64::max_value()] {
+            let security = SecurityOptions::GcloudIot(("project".to_owned(), vec![1, 2, 3], *expiry));
+            let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+            assert_eq!(opts.token_lifetime(), None);
+        }
+    }
+
+    #[test]
+    fn cloud_presets_should_build_connect_packet() {
+        use mqttoptions::{AwsCredentials, AwsCustomAuthorizer};
+
+        let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=".to_owned();
+        let security = SecurityOptions::AzureIotHub(("hub.azure-devices.net".to_owned(), "device-1".to_owned(), key, 60));
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        let connect = opts.connect_packet().unwrap();
+        assert_eq!(connect.client_id, "device-1");
+        assert_eq!(connect.username, Some("hub.azure-devices.net/device-1/?api-version=2018-06-30".to_owned()));
+        let password = connect.password.unwrap();
+        assert!(password.starts_with("SharedAccessSignature sr=hub.azure-devices.net%2Fdevices%2Fdevice-1&sig="));
+        assert_eq!(opts.token_lifetime(), Some(Duration::from_secs(3600)));
+
+        let max_expiry = 365 * 24 * 60;
+        let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        for &expiry in &[0, -1, max_expiry + 1, i64::min_value(), i64::max_value()] {
+            let security = SecurityOptions::AzureIotHub(("hub".to_owned(), "device".to_owned(), key.to_owned(), expiry));
+            let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+            assert_eq!(opts.token_lifetime(), None);
+            match opts.connect_packet() {
+                Err(ConnectError::InvalidTokenExpiry(e)) => assert_eq!(e, expiry),
+                v => panic!("Should throw invalid expiry error. Got = {:?}", v),
+            }
+        }
+
+        let security = SecurityOptions::AzureIotHub(("hub".to_owned(), "device".to_owned(), key.to_owned(), max_expiry));
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        assert_eq!(opts.token_lifetime(), Some(Duration::from_secs(365 * 24 * 3600)));
+
+        for &(key, expiry) in &[("not base64!", 60), ("", 0), ("", i64::max_value())] {
+            let security = SecurityOptions::AzureIotHub(("hub".to_owned(), "device".to_owned(), key.to_owned(), expiry));
+            match MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security).connect_packet() {
+                Err(ConnectError::InvalidDeviceKey) | Err(ConnectError::InvalidTokenExpiry(_)) => (),
+                v => panic!("Should throw invalid key or expiry error. Got = {:?}", v),
+            }
+        }
+
+        let credentials = AwsCredentials::new("AKIDEXAMPLE", "secret", "us-east-1");
+        let security = SecurityOptions::AwsSigV4(credentials);
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        match opts.connect_packet() {
+            Err(ConnectError::AwsSigV4WithoutWebSocket) => (),
+            v => panic!("Should throw sigv4 without websocket error. Got = {:?}", v),
+        }
+        let opts = opts.set_connection_method(ConnectionMethod::WebSocket("/mqtt".to_owned(), Vec::new()));
+        assert_eq!(opts.connect_packet().unwrap().username, None);
+
+        let authorizer = AwsCustomAuthorizer::new("authorizer").set_token("key", "token").set_password("secret");
+        let security = SecurityOptions::AwsCustomAuthorizer(authorizer);
+        let connect = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security).connect_packet().unwrap();
+        assert_eq!(connect.client_id, "test-id");
+        assert_eq!(connect.username, Some("?x-amz-customauthorizer-name=authorizer&key=token".to_owned()));
+        assert_eq!(connect.password, Some("secret".to_owned()));
+    }
 }
@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+# Regenerates the certificates used by the unit tests. Files are committed so that the
+# tests don't need openssl. Certificates are valid for 100 years. Certificates for real
+# brokers are generated with utils/certgenerator
+set -euo pipefail
+cd "$(dirname "$0")"
+
+DAYS=36500
+
+# root ca
+openssl req -x509 -newkey rsa:2048 -nodes -days $DAYS -subj "/CN=rumqtt test ca" \
+    -keyout ca.key.pem -out ca.cert.pem \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+# broker certificate for localhost
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" -keyout server.key.pem -out server.csr
+openssl x509 -req -in server.csr -CA ca.cert.pem -CAkey ca.key.pem -CAcreateserial -days $DAYS -out server.cert.pem \
+    -extfile <(printf "subjectAltName=DNS:localhost\nextendedKeyUsage=serverAuth\nbasicConstraints=CA:FALSE")
+
+# client certificate and a pkcs12 archive of it without password. Legacy algorithms
+# so that the archive can be read by every native tls backend
+openssl req -newkey rsa:2048 -nodes -subj "/CN=client" -keyout client.key.pem -out client.csr
+openssl x509 -req -in client.csr -CA ca.cert.pem -CAkey ca.key.pem -CAcreateserial -days $DAYS -out client.cert.pem \
+    -extfile <(printf "extendedKeyUsage=clientAuth\nbasicConstraints=CA:FALSE")
+openssl pkcs12 -export -in client.cert.pem -inkey client.key.pem -certfile ca.cert.pem -passout pass: \
+    -certpbe PBE-SHA1-3DES -keypbe PBE-SHA1-3DES -macalg sha1 -out client.p12
+
+# sec1 encoded ec key (`EC PRIVATE KEY` pem)
+openssl ecparam -name prime256v1 -genkey -noout -out client.ec.key.pem
+
+# self signed certificate which has nothing to do with the ca
+openssl req -x509 -newkey rsa:2048 -nodes -days $DAYS -subj "/CN=localhost" -keyout /dev/null -out other.cert.pem \
+    -addext "subjectAltName=DNS:localhost"
+
+# der forms for pinning
+openssl x509 -in server.cert.pem -outform DER -out server.cert.der
+openssl x509 -in ca.cert.pem -outform DER -out ca.cert.der
+openssl x509 -in other.cert.pem -outform DER -out other.cert.der
+openssl x509 -in server.cert.pem -pubkey -noout | openssl pkey -pubin -outform DER -out server.pubkey.der
+
+rm -f server.csr client.csr ca.cert.srl other.cert.pem